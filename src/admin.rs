@@ -0,0 +1,205 @@
+//! 管理接口：查看运行状态，修改缓存、规则与上游，作用于运行中的实例
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+use tracing::info;
+
+use crate::{
+    config::RuleConfig,
+    http::{Request, Response},
+    message::rtype,
+    pause::PauseStatus,
+    resolver::Resolver,
+};
+
+/// `PUT /upstreams/<组名>` 的前缀
+pub const UPSTREAMS: &str = "/upstreams/";
+/// 临时规则未指定 `expire` 时的有效期
+const DEFAULT_EXPIRE: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Serialize)]
+struct Status {
+    version: &'static str,
+    uptime_secs: u64,
+    default: String,
+    upstreams: BTreeMap<String, Vec<String>>,
+    rules: Vec<RuleStatus>,
+    temporary: Vec<RuleStatus>,
+    blocking_paused: PauseStatus,
+    cache_entries: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RuleStatus {
+    name: String,
+    entries: usize,
+    /// 临时规则的剩余秒数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheEntry {
+    upstream: String,
+    server: String,
+    name: String,
+    qtype: String,
+    expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamServers {
+    servers: Vec<String>,
+}
+
+pub async fn status(resolver: &Resolver) -> Response {
+    let mut cache_entries = 0;
+    let mut upstreams = BTreeMap::new();
+    for (name, upstream) in resolver.upstreams() {
+        cache_entries += upstream.cache_entries().await.len();
+        let servers = upstream.servers().iter().map(|s| s.to_string()).collect();
+        upstreams.insert(name.clone(), servers);
+    }
+
+    let rules = resolver
+        .router()
+        .rules()
+        .iter()
+        .map(|rule| RuleStatus {
+            name: rule.name.clone(),
+            entries: rule.trie.len(),
+            expires_in: None,
+        })
+        .collect();
+    let temporary = resolver
+        .temporary()
+        .list()
+        .into_iter()
+        .map(|(rule, ttl)| RuleStatus {
+            name: rule.name.clone(),
+            entries: rule.trie.len(),
+            expires_in: Some(ttl.as_secs()),
+        })
+        .collect();
+
+    Response::json(&Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: resolver.uptime().as_secs(),
+        default: resolver.config().default,
+        upstreams,
+        rules,
+        temporary,
+        blocking_paused: resolver.pause().status(),
+        cache_entries,
+    })
+}
+
+pub async fn cache(resolver: &Resolver) -> Response {
+    let mut entries = Vec::new();
+    for (name, upstream) in resolver.upstreams() {
+        for (server, qname, qtype, ttl) in upstream.cache_entries().await {
+            entries.push(CacheEntry {
+                upstream: name.clone(),
+                server: server.to_string(),
+                name: qname,
+                qtype: rtype::name(qtype),
+                expires_in: ttl.as_secs(),
+            });
+        }
+    }
+    entries.sort_by(|a, b| (&a.name, &a.qtype).cmp(&(&b.name, &b.qtype)));
+    Response::json(&entries)
+}
+
+/// `?name=` 只删除该域名，否则清空全部缓存
+pub async fn flush_cache(request: &Request, resolver: &Resolver) -> Response {
+    let name = request.param("name").map(|n| n.trim_end_matches('.'));
+    let mut flushed = 0;
+    for upstream in resolver.upstreams().values() {
+        flushed += upstream.flush_cache(name).await;
+    }
+    Response::json(&BTreeMap::from([("flushed", flushed)]))
+}
+
+pub async fn reload_rules(resolver: &Resolver) -> Response {
+    match resolver.reload_rules().await {
+        Ok(rules) => Response::json(&BTreeMap::from([("rules", rules)])),
+        Err(e) => Response::error(e),
+    }
+}
+
+/// 请求体为 JSON 格式的规则，写法同配置文件，`?expire=` 为有效秒数
+pub fn add_temporary(request: &Request, resolver: &Resolver) -> Response {
+    let expire = match request.param("expire").map(str::parse::<u64>) {
+        None => DEFAULT_EXPIRE,
+        Some(Ok(secs)) if secs > 0 => Duration::from_secs(secs),
+        Some(_) => return Response::text(400, "bad expire\n"),
+    };
+    let rule: RuleConfig = match serde_json::from_slice(&request.body) {
+        Ok(rule) => rule,
+        Err(e) => return Response::text(400, format!("{e}\n")),
+    };
+    match resolver.add_temporary(rule, expire) {
+        Ok(()) => Response::text(200, "ok\n"),
+        Err(e) => Response::error(e),
+    }
+}
+
+pub fn remove_temporary(request: &Request, resolver: &Resolver) -> Response {
+    let Some(name) = request.param("name") else {
+        return Response::text(400, "missing name\n");
+    };
+    match resolver.temporary().remove(name) {
+        true => Response::text(200, "ok\n"),
+        false => Response::text(404, "no such temporary rule\n"),
+    }
+}
+
+/// `?client=` 只针对该客户端，否则对全部客户端生效
+fn client(request: &Request) -> Result<Option<IpAddr>, Response> {
+    request
+        .param("client")
+        .map(str::parse)
+        .transpose()
+        .map_err(|_| Response::text(400, "bad client\n"))
+}
+
+pub fn pause_blocking(request: &Request, resolver: &Resolver) -> Response {
+    let client = match client(request) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let duration = match request.param("duration").map(str::parse::<u64>) {
+        Some(Ok(secs)) if secs > 0 => Duration::from_secs(secs),
+        _ => return Response::text(400, "duration must be a positive number of seconds\n"),
+    };
+    if let Err(e) = resolver.pause().pause(client, duration) {
+        return Response::error(e);
+    }
+    info!(client = ?client, secs = duration.as_secs(), "blocking paused");
+    Response::json(&resolver.pause().status())
+}
+
+pub fn resume_blocking(request: &Request, resolver: &Resolver) -> Response {
+    let client = match client(request) {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    if resolver.pause().resume(client) {
+        info!(client = ?client, "blocking resumed");
+    }
+    Response::json(&resolver.pause().status())
+}
+
+/// 请求体为 `{"servers": ["1.1.1.1:53"]}`
+pub async fn set_upstream(request: &Request, resolver: &Resolver) -> Response {
+    let name = &request.path[UPSTREAMS.len()..];
+    let body: UpstreamServers = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => return Response::text(400, format!("{e}\n")),
+    };
+    match resolver.set_upstream_servers(name, body.servers).await {
+        Ok(()) => Response::text(200, "ok\n"),
+        Err(e) => Response::error(e),
+    }
+}
@@ -1,9 +1,17 @@
 use std::cmp::Ordering;
 use std::env;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::PathBuf;
 
+#[cfg(test)]
+#[path = "../trie/compiled.rs"]
+mod compiled;
+#[path = "../trie/format.rs"]
+mod format;
+
+use format::{checksum, MAGIC, VERSION};
+
 fn reverse_domain(domain: &str) -> String {
     let mut parts: Vec<&str> = domain.split('.').collect();
     parts.reverse();
@@ -138,22 +146,57 @@ fn subdomain_priority(sub: &str) -> i32 {
     }
 }
 
+/// 把 (域名, 行号) 写成编译格式，布局见 `format`
+fn write_compiled<W: Write>(domains: &[(String, u32)], mut out: W) -> io::Result<()> {
+    let mut keys: Vec<(String, u32)> = domains
+        .iter()
+        .map(|(domain, line)| {
+            let labels: Vec<&str> = domain.split('.').rev().collect();
+            (labels.join(".").to_ascii_lowercase(), *line)
+        })
+        .collect();
+    keys.sort();
+    keys.dedup_by(|a, b| a.0 == b.0);
+
+    let count = keys.len();
+    let mut body = Vec::with_capacity((count * 2 + 1) * 4 + count * 20);
+    let mut offset = 0u32;
+    body.extend_from_slice(&offset.to_le_bytes());
+    for (key, _) in &keys {
+        offset += key.len() as u32;
+        body.extend_from_slice(&offset.to_le_bytes());
+    }
+    for (_, line) in &keys {
+        body.extend_from_slice(&line.to_le_bytes());
+    }
+    for (key, _) in &keys {
+        body.extend_from_slice(key.as_bytes());
+    }
+
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(count as u32).to_le_bytes())?;
+    out.write_all(&checksum(&body).to_le_bytes())?;
+    out.write_all(&body)
+}
+
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 4 {
-        eprintln!("用法: build_domains -i <input1> [input2 ...] -o <output>");
+        eprintln!("用法: build_domains -i <input1> [input2 ...] -o <output> [--compile]");
         std::process::exit(1);
     }
 
     let mut inputs: Vec<PathBuf> = Vec::new();
     let mut output: Option<PathBuf> = None;
+    let mut compile = false;
 
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
             "-i" => {
                 i += 1;
-                while i < args.len() && args[i] != "-o" {
+                while i < args.len() && args[i] != "-o" && args[i] != "--compile" {
                     inputs.push(PathBuf::from(&args[i]));
                     i += 1;
                 }
@@ -165,6 +208,7 @@ fn main() -> io::Result<()> {
                     output = Some(PathBuf::from(&args[i]));
                 }
             }
+            "--compile" => compile = true,
             _ => {}
         }
         i += 1;
@@ -203,10 +247,78 @@ fn main() -> io::Result<()> {
     let domains = sorted;
 
     // 写入输出文件
-    let mut f = File::create(&output)?;
-    for domain in domains {
-        writeln!(f, "{}", domain)?;
+    let mut f = BufWriter::new(File::create(&output)?);
+    if compile {
+        // 行号与文本格式输出时一致
+        let domains: Vec<(String, u32)> = domains
+            .into_iter()
+            .enumerate()
+            .map(|(i, domain)| (domain, i as u32 + 1))
+            .collect();
+        write_compiled(&domains, &mut f)?;
+    } else {
+        for domain in domains {
+            writeln!(f, "{}", domain)?;
+        }
     }
+    f.flush()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiled::CompiledTrie;
+    use std::env::temp_dir;
+
+    const HEADER_LEN: usize = 24;
+
+    #[test]
+    fn it_work_compiled_roundtrip() {
+        let domains = vec![
+            ("google.com".to_string(), 1),
+            ("www.youtube.com".to_string(), 2),
+            ("youtube.com".to_string(), 3),
+            ("t.co".to_string(), 4),
+        ];
+        let path = temp_dir().join(format!("fakedns-compiled-{}.bin", std::process::id()));
+        write_compiled(&domains, File::create(&path).unwrap()).unwrap();
+
+        let trie = CompiledTrie::try_from(path.as_path()).unwrap();
+        assert_eq!(trie.len(), 4);
+        assert_eq!(trie.find(["com", "google", "www"]), Some((2, 1)));
+        assert_eq!(trie.find(["com", "youtube", "www"]), Some((2, 3)));
+        assert_eq!(trie.find(["co", "T"]), Some((2, 4)));
+        assert_eq!(trie.find(["com"]), None);
+        assert_eq!(trie.find(["com", "googl"]), None);
+
+        // 篡改内容后校验失败
+        let mut data = std::fs::read(&path).unwrap();
+        assert!(CompiledTrie::is_compiled(&data));
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert!(CompiledTrie::try_from(path.as_path()).is_err());
+
+        // 校验和正确但 offsets 乱序、count 过大，同样拒绝
+        data[last] ^= 1;
+        let reseal = |data: &mut Vec<u8>| {
+            let sum = checksum(&data[HEADER_LEN..]);
+            data[16..24].copy_from_slice(&sum.to_le_bytes());
+            std::fs::write(&path, &data).unwrap();
+        };
+        let mut swapped = data.clone();
+        swapped[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&100u32.to_le_bytes());
+        reseal(&mut swapped);
+        assert!(CompiledTrie::try_from(path.as_path()).is_err());
+        let mut huge = data.clone();
+        huge[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        reseal(&mut huge);
+        assert!(CompiledTrie::try_from(path.as_path()).is_err());
+
+        reseal(&mut data);
+        assert!(CompiledTrie::try_from(path.as_path()).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
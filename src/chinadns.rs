@@ -0,0 +1,86 @@
+use std::io::{Error, Result};
+
+use crate::{
+    config::ChinaDnsConfig,
+    message::{rcode, Message},
+    payload::Payload,
+    trie::IpTrie,
+};
+
+/// 未命中规则的域名同时查询国内外上游，国内应答的地址均在国内网段时才采用
+pub struct ChinaDns {
+    pub domestic: String,
+    pub foreign: String,
+    cidr: IpTrie,
+}
+
+impl TryFrom<&ChinaDnsConfig> for ChinaDns {
+    type Error = Error;
+
+    fn try_from(config: &ChinaDnsConfig) -> Result<Self> {
+        let mut cidr = IpTrie::default();
+        for file in &config.cidr {
+            cidr.extend_file(file)
+                .map_err(|e| Error::new(e.kind(), format!("chinadns {file:?}: {e}")))?;
+        }
+        cidr.shrink_to_fit();
+
+        Ok(Self {
+            domestic: config.domestic.clone(),
+            foreign: config.foreign.clone(),
+            cidr,
+        })
+    }
+}
+
+impl ChinaDns {
+    pub fn len(&self) -> usize {
+        self.cidr.len()
+    }
+
+    /// 国内应答可用：解析成功、非 SERVFAIL/REFUSED，且所有 A/AAAA 地址都在国内网段
+    pub fn trusts(&self, response: &Payload) -> bool {
+        Message::try_from(response).is_ok_and(|message| {
+            !matches!(message.rcode(), rcode::SERVFAIL | rcode::REFUSED)
+                && message.answer_ips().all(|(ip, _)| self.cidr.contains(ip))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{rtype, RData, Record};
+
+    fn response(rcode: u8, ips: &[&str]) -> Payload {
+        let mut message = Message::reply(&Message::query("example.com", rtype::A));
+        message.set_rcode(rcode);
+        for ip in ips {
+            let data = match ip.parse().unwrap() {
+                std::net::IpAddr::V4(ip) => RData::A(ip),
+                std::net::IpAddr::V6(ip) => RData::Aaaa(ip),
+            };
+            message.answers.push(Record::new("example.com", 60, data));
+        }
+        Payload::try_from(&message).unwrap()
+    }
+
+    #[test]
+    fn it_work_trusts() {
+        let mut cidr = IpTrie::default();
+        cidr.extend_content("cn", "1.0.1.0/24\n240e::/20\n")
+            .unwrap();
+        let china = ChinaDns {
+            domestic: "direct".into(),
+            foreign: "proxy".into(),
+            cidr,
+        };
+
+        assert!(china.trusts(&response(0, &["1.0.1.1", "240e::1"])));
+        assert!(!china.trusts(&response(0, &["1.0.1.1", "8.8.8.8"])));
+        // 无地址的应答（CNAME、NXDOMAIN 等）照常采用
+        assert!(china.trusts(&response(rcode::NXDOMAIN, &[])));
+        assert!(!china.trusts(&response(rcode::SERVFAIL, &[])));
+        assert!(!china.trusts(&Payload::from(&[0u8; 3][..])));
+    }
+}
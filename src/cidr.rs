@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// 地址段，如 `198.18.0.0/15`，不带前缀长度时表示单个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+/// 地址统一展开为 128 位，IPv4 占低 32 位
+pub fn ip_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    if ip.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid cidr {s:?}"));
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max_prefix(addr),
+        };
+        if prefix > max_prefix(addr) {
+            return Err(invalid());
+        }
+
+        // 清除主机位
+        let cidr = Self { addr, prefix };
+        let network = ip_bits(addr) & cidr.mask();
+        let addr = match addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(network as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(network)),
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Cidr {
+    fn mask(&self) -> u128 {
+        let host_bits = (max_prefix(self.addr) - self.prefix) as u32;
+        let width_mask = if self.addr.is_ipv4() {
+            u32::MAX as u128
+        } else {
+            u128::MAX
+        };
+        width_mask.checked_shl(host_bits).unwrap_or(0) & width_mask
+    }
+
+    /// 段内地址数量（/0 的 IPv6 段按 u128::MAX 计）
+    pub fn size(&self) -> u128 {
+        let host_bits = (max_prefix(self.addr) - self.prefix) as u32;
+        1u128.checked_shl(host_bits).unwrap_or(u128::MAX)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.addr.is_ipv4() == ip.is_ipv4() && ip_bits(ip) & self.mask() == ip_bits(self.addr)
+    }
+
+    /// 段内第 offset 个地址
+    pub fn nth(&self, offset: u128) -> IpAddr {
+        let bits = ip_bits(self.addr) + offset;
+        match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_cidr() {
+        let cidr: Cidr = "198.19.1.2/15".parse().unwrap();
+        assert_eq!(cidr.to_string(), "198.18.0.0/15");
+        assert_eq!(cidr.size(), 1 << 17);
+        assert!(cidr.contains("198.19.255.255".parse().unwrap()));
+        assert!(!cidr.contains("198.20.0.0".parse().unwrap()));
+        assert!(!cidr.contains("::ffff:198.18.0.1".parse().unwrap()));
+        assert_eq!(cidr.nth(257), "198.18.1.1".parse::<IpAddr>().unwrap());
+
+        let cidr: Cidr = "fd00::1/64".parse().unwrap();
+        assert_eq!(cidr.to_string(), "fd00::/64");
+        assert_eq!(cidr.size(), 1 << 64);
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("8.8.8.8".parse().unwrap()));
+        assert_eq!("1.2.3.4".parse::<Cidr>().unwrap().prefix, 32);
+        assert!("1.2.3.4/33".parse::<Cidr>().is_err());
+    }
+}
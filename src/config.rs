@@ -0,0 +1,917 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cidr::Cidr,
+    message::{rtype, RData},
+    nftset::NftSet,
+    upstream::QUERY_TIMEOUT,
+};
+
+pub const DIRECT: &str = "direct";
+pub const PROXY: &str = "proxy";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// 未命中任何规则时使用的上游组
+    #[serde(default = "default_upstream")]
+    pub default: String,
+
+    /// 关闭时保存上游缓存与统计快照，启动时恢复缓存
+    pub state_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+
+    /// 按顺序匹配，第一条命中的规则生效
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+
+    /// 本地记录，优先于路由规则
+    #[serde(default)]
+    pub local: LocalConfig,
+
+    /// 权威区域，优先于路由规则
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+
+    /// `fake_ip` 动作使用的地址池
+    pub fake_ip: Option<FakeIpConfig>,
+
+    /// 按地址反查域名的 Unix socket
+    pub reverse: Option<ReverseConfig>,
+
+    #[serde(default)]
+    pub nftset: NftSetGlobalConfig,
+
+    /// 未命中规则的域名同时查询国内外上游，按地址是否在国内网段选择应答
+    pub chinadns: Option<ChinaDnsConfig>,
+
+    /// 按应答地址改写上游应答
+    #[serde(default)]
+    pub filter: FilterConfig,
+
+    /// 应答 TTL 的上下限，规则可以单独覆盖
+    #[serde(default)]
+    pub ttl: TtlConfig,
+
+    /// 每个应答一条记录的查询日志
+    pub query_log: Option<QueryLogConfig>,
+
+    /// 以 dnstap 输出客户端与上游的原始报文
+    pub dnstap: Option<DnstapConfig>,
+
+    /// 管理用的 HTTP 服务，提供 `/metrics`
+    pub http: Option<HttpConfig>,
+
+    /// 查询最多的域名与客户端的近似统计，通过 `/top` 查看
+    pub top: Option<TopConfig>,
+}
+
+fn default_upstream() -> String {
+    DIRECT.into()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub servers: Vec<String>,
+
+    /// 污染应答常用的地址，应答含这些地址时丢弃并继续等待
+    #[serde(default)]
+    pub bogus_ip: Vec<Cidr>,
+
+    /// 请求带 EDNS 时丢弃不带 OPT 的应答（注入的应答通常不带）
+    #[serde(default)]
+    pub require_edns: bool,
+
+    /// 收到首个应答后再等待的毫秒数，期间到达的应答优先（注入的应答先到）
+    #[serde(default)]
+    pub wait: u64,
+
+    /// 每隔多少秒更换一次源端口，0 为不更换
+    #[serde(default = "default_rotate")]
+    pub rotate: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            bogus_ip: Vec::new(),
+            require_edns: false,
+            wait: 0,
+            rotate: default_rotate(),
+        }
+    }
+}
+
+fn default_rotate() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+
+    /// 域名列表文件，每行一个域名
+    pub file: Option<PathBuf>,
+
+    /// 内联域名
+    #[serde(default)]
+    pub domains: Vec<String>,
+
+    pub action: Action,
+
+    /// 转发应答中的地址写入这些 nftables 集合
+    #[serde(default)]
+    pub nftset: Vec<NftSet>,
+
+    /// 这些类型的查询直接应答 NODATA，如 `["AAAA", "HTTPS"]`
+    #[serde(default)]
+    pub nodata: Vec<String>,
+
+    /// 从转发应答中删除这些类型的记录
+    #[serde(default)]
+    pub strip: Vec<String>,
+
+    /// 未设置的上下限沿用全局 `[ttl]`
+    #[serde(default)]
+    pub ttl: TtlConfig,
+}
+
+impl RuleConfig {
+    pub fn nodata_types(&self) -> Result<Vec<u16>> {
+        parse_types(&self.nodata)
+    }
+
+    pub fn strip_types(&self) -> Result<Vec<u16>> {
+        parse_types(&self.strip)
+    }
+}
+
+/// 记录类型名，如 `AAAA`、`HTTPS`、`TYPE65`
+fn parse_types(names: &[String]) -> Result<Vec<u16>> {
+    names
+        .iter()
+        .map(|name| {
+            rtype::from_name(name).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown record type {name:?}"),
+                )
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// 转发到指定上游组
+    Forward(String),
+    /// 拦截
+    Block(BlockMode),
+    /// 以静态记录应答
+    Static(Vec<StaticRecord>),
+    /// A/AAAA 以地址池中的虚假地址应答，其它类型转发到指定上游组
+    FakeIp(String),
+    /// 改为解析另一个域名，以 CNAME 或展平后的记录应答
+    Rewrite(RewriteAction),
+    /// 转发后替换应答中的地址，其余部分保留
+    Override(OverrideAction),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteAction {
+    pub target: String,
+
+    /// 直接以目标的记录应答，不返回 CNAME
+    #[serde(default)]
+    pub flatten: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverrideAction {
+    /// 转发的上游组
+    pub forward: String,
+    pub ips: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockMode {
+    /// A 应答 0.0.0.0，AAAA 应答 ::
+    #[default]
+    ZeroIp,
+    Nxdomain,
+    Nodata,
+    Refused,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticRecord {
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub value: String,
+    #[serde(default = "default_static_ttl")]
+    pub ttl: u32,
+}
+
+fn default_static_ttl() -> u32 {
+    500
+}
+
+impl StaticRecord {
+    pub fn parse(&self) -> Result<(u16, RData)> {
+        parse_record(&self.rtype, &self.value)
+    }
+}
+
+fn parse_record(rtype: &str, value: &str) -> Result<(u16, RData)> {
+    let rtype = rtype::from_name(rtype).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown record type {rtype:?}"),
+        )
+    })?;
+    Ok((rtype, RData::from_text(rtype, value)?))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalConfig {
+    /// hosts 格式文件
+    #[serde(default)]
+    pub hosts: Vec<PathBuf>,
+
+    #[serde(default)]
+    pub records: Vec<LocalRecord>,
+
+    /// hosts 记录与合成 PTR 的 TTL
+    #[serde(default = "default_local_ttl")]
+    pub ttl: u32,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            hosts: Vec::new(),
+            records: Vec::new(),
+            ttl: default_local_ttl(),
+        }
+    }
+}
+
+fn default_local_ttl() -> u32 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeIpConfig {
+    #[serde(default = "default_fake_ipv4")]
+    pub ipv4: Option<Cidr>,
+    pub ipv6: Option<Cidr>,
+
+    /// 应答的 TTL
+    #[serde(default = "default_fake_ip_ttl")]
+    pub ttl: u32,
+
+    /// 映射在最后一次查询后保留的秒数
+    #[serde(default = "default_fake_ip_expire")]
+    pub expire: u64,
+
+    /// 映射表持久化文件
+    pub persist: Option<PathBuf>,
+}
+
+impl Default for FakeIpConfig {
+    fn default() -> Self {
+        Self {
+            ipv4: default_fake_ipv4(),
+            ipv6: None,
+            ttl: default_fake_ip_ttl(),
+            expire: default_fake_ip_expire(),
+            persist: None,
+        }
+    }
+}
+
+fn default_fake_ipv4() -> Option<Cidr> {
+    "198.18.0.0/15".parse().ok()
+}
+
+fn default_fake_ip_ttl() -> u32 {
+    1
+}
+
+fn default_fake_ip_expire() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseConfig {
+    pub socket: PathBuf,
+
+    /// 最多记录的转发应答地址数
+    #[serde(default = "default_reverse_capacity")]
+    pub capacity: usize,
+
+    /// 转发应答地址的最短保留秒数
+    #[serde(default = "default_reverse_ttl")]
+    pub ttl: u64,
+}
+
+fn default_reverse_capacity() -> usize {
+    65536
+}
+
+fn default_reverse_ttl() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChinaDnsConfig {
+    #[serde(default = "default_upstream")]
+    pub domestic: String,
+
+    #[serde(default = "default_foreign")]
+    pub foreign: String,
+
+    /// 国内网段列表文件，每行一个 CIDR
+    pub cidr: Vec<PathBuf>,
+}
+
+fn default_foreign() -> String {
+    PROXY.into()
+}
+
+impl ChinaDnsConfig {
+    /// 旧版命令行参数对应的 direct/proxy 分组
+    pub fn legacy(cidr: &Path) -> Self {
+        Self {
+            domestic: DIRECT.into(),
+            foreign: PROXY.into(),
+            cidr: vec![cidr.into()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    /// 应答含这些地址时改为 NXDOMAIN，同 dnsmasq `bogus-nxdomain`
+    #[serde(default)]
+    pub bogus_nxdomain: Vec<Cidr>,
+
+    /// 应答含这些地址时按 `block_mode` 拦截
+    #[serde(default)]
+    pub block_ip: Vec<Cidr>,
+
+    /// 每行一个 CIDR 的拦截地址列表
+    #[serde(default)]
+    pub block_ip_file: Vec<PathBuf>,
+
+    #[serde(default)]
+    pub block_mode: BlockMode,
+
+    /// 上游应答中的内网、回环、链路本地地址视为 DNS rebinding
+    pub rebind: Option<RebindConfig>,
+}
+
+impl FilterConfig {
+    pub fn is_empty(&self) -> bool {
+        self.bogus_nxdomain.is_empty()
+            && self.block_ip.is_empty()
+            && self.block_ip_file.is_empty()
+            && self.rebind.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RebindConfig {
+    #[serde(default)]
+    pub mode: RebindMode,
+
+    /// 允许解析到内网地址的域名（含子域名）
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RebindMode {
+    /// 删除这些地址，全部删除后为 NODATA
+    #[default]
+    Strip,
+    /// 整个应答改为 REFUSED
+    Refuse,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TtlConfig {
+    /// 低于该值的 TTL 提高到该值
+    pub min: Option<u32>,
+    /// 高于该值的 TTL 降低到该值
+    pub max: Option<u32>,
+}
+
+impl TtlConfig {
+    /// 逐项回退到 `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            min: self.min.or(other.min),
+            max: self.max.or(other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+
+    pub fn clamp(&self, ttl: u32) -> u32 {
+        let ttl = self.min.map_or(ttl, |min| ttl.max(min));
+        self.max.map_or(ttl, |max| ttl.min(max))
+    }
+
+    fn validate(&self, scope: &str) -> Result<()> {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min > max => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{scope} ttl min {min} above max {max}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryLogConfig {
+    pub path: PathBuf,
+
+    #[serde(default)]
+    pub format: QueryLogFormat,
+
+    /// 单个文件的字节数上限，超过后轮转
+    #[serde(default = "default_query_log_max_size")]
+    pub max_size: u64,
+
+    /// 保留的历史文件数，为 0 时直接截断
+    #[serde(default = "default_query_log_max_files")]
+    pub max_files: usize,
+
+    /// 每 N 个查询记录一个
+    #[serde(default = "default_query_log_sample")]
+    pub sample: u32,
+
+    /// 只记录这些客户端，为空时记录所有客户端
+    #[serde(default)]
+    pub clients: Vec<Cidr>,
+
+    /// 不记录这些客户端，如监控探测
+    #[serde(default)]
+    pub exclude_clients: Vec<Cidr>,
+}
+
+fn default_query_log_max_size() -> u64 {
+    64 << 20
+}
+
+fn default_query_log_max_files() -> usize {
+    5
+}
+
+fn default_query_log_sample() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueryLogFormat {
+    #[default]
+    Text,
+    /// JSON Lines
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnstapConfig {
+    /// Frame Streams 收集端的 Unix socket，与 `file` 二选一
+    pub socket: Option<PathBuf>,
+
+    /// 写入文件，启动时覆盖
+    pub file: Option<PathBuf>,
+
+    /// 写入每条消息的 identity，通常为主机名
+    pub identity: Option<String>,
+
+    /// 输出 CLIENT_QUERY/CLIENT_RESPONSE
+    #[serde(default = "default_true")]
+    pub client: bool,
+
+    /// 输出 FORWARDER_QUERY/FORWARDER_RESPONSE
+    #[serde(default = "default_true")]
+    pub forwarder: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopConfig {
+    /// 每类最多跟踪的条目数，超出后替换计数最小者
+    #[serde(default = "default_top_capacity")]
+    pub capacity: usize,
+}
+
+fn default_top_capacity() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// 不做鉴权，应只监听本机或内网地址
+    pub listen: SocketAddr,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NftSetGlobalConfig {
+    /// 不写入内核，改为以 nft 语法追加到该文件
+    pub dry_run: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    /// 区域名，即区域文件的默认 $ORIGIN
+    pub name: String,
+    /// RFC 1035 主文件格式
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub value: String,
+    pub ttl: Option<u32>,
+}
+
+impl LocalRecord {
+    pub fn parse(&self) -> Result<(u16, RData)> {
+        parse_record(&self.rtype, &self.value)
+    }
+}
+
+impl TryFrom<&Path> for Config {
+    type Error = Error;
+
+    fn try_from(filename: &Path) -> Result<Self> {
+        let content = read_to_string(filename)?;
+        let config: Self = toml::from_str(&content)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{filename:?}: {e}")))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// 与旧版命令行参数等价的路由表：exclude → direct，block → 拦截，proxy → proxy，其余 → direct
+    pub fn legacy(domain: &Path, block_domain: &Path, exclude_domain: &Path) -> Self {
+        let rule = |name: &str, file: &Path, action| RuleConfig {
+            name: name.into(),
+            file: Some(file.into()),
+            domains: Vec::new(),
+            action,
+            nftset: Vec::new(),
+            nodata: Vec::new(),
+            strip: Vec::new(),
+            ttl: TtlConfig::default(),
+        };
+        let upstream = |server: &str| UpstreamConfig {
+            servers: vec![server.into()],
+            ..Default::default()
+        };
+
+        Self {
+            default: DIRECT.into(),
+            state_dir: None,
+            upstreams: BTreeMap::from([
+                (DIRECT.into(), upstream("223.5.5.5:53")),
+                (PROXY.into(), upstream("8.8.8.8:53")),
+            ]),
+            rules: vec![
+                rule("exclude", exclude_domain, Action::Forward(DIRECT.into())),
+                rule("block", block_domain, Action::Block(BlockMode::ZeroIp)),
+                rule(PROXY, domain, Action::Forward(PROXY.into())),
+            ],
+            local: LocalConfig::default(),
+            zones: Vec::new(),
+            fake_ip: None,
+            reverse: None,
+            nftset: NftSetGlobalConfig::default(),
+            chinadns: None,
+            filter: FilterConfig::default(),
+            ttl: TtlConfig::default(),
+            query_log: None,
+            dnstap: None,
+            http: None,
+            top: None,
+        }
+    }
+
+    /// 同 `legacy`，但 proxy 列表以虚假地址应答
+    pub fn legacy_fake_ip(domain: &Path, block_domain: &Path, exclude_domain: &Path) -> Self {
+        let mut config = Self::legacy(domain, block_domain, exclude_domain);
+        for rule in config.rules.iter_mut().filter(|r| r.name == PROXY) {
+            rule.action = Action::FakeIp(PROXY.into());
+        }
+        config.fake_ip = Some(FakeIpConfig::default());
+        config
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+
+        self.ttl.validate("global")?;
+        let mut groups = vec![&self.default];
+        for rule in &self.rules {
+            if rule.file.is_none() && rule.domains.is_empty() {
+                return invalid(format!("rule {:?} has neither file nor domains", rule.name));
+            }
+            // 规则只设一端时与全局的另一端组合
+            rule.ttl
+                .or(self.ttl)
+                .validate(&format!("rule {:?}", rule.name))?;
+            rule.nodata_types()?;
+            rule.strip_types()?;
+            match &rule.action {
+                Action::Forward(group) => groups.push(group),
+                Action::Static(records) => {
+                    for record in records {
+                        record.parse()?;
+                    }
+                }
+                Action::FakeIp(group) => {
+                    if self.fake_ip.is_none() {
+                        return invalid(format!(
+                            "rule {:?} uses fake_ip without [fake_ip]",
+                            rule.name
+                        ));
+                    }
+                    groups.push(group);
+                }
+                Action::Rewrite(rewrite) => {
+                    if rewrite.target.trim_end_matches('.').is_empty() {
+                        return invalid(format!("rule {:?} rewrite without target", rule.name));
+                    }
+                }
+                Action::Override(action) => {
+                    if action.ips.is_empty() {
+                        return invalid(format!("rule {:?} override without ips", rule.name));
+                    }
+                    groups.push(&action.forward);
+                }
+                Action::Block(_) => {}
+            }
+        }
+
+        for record in &self.local.records {
+            record.parse()?;
+        }
+
+        if let Some(query_log) = &self.query_log {
+            if query_log.sample == 0 {
+                return invalid("query_log sample must be at least 1".into());
+            }
+        }
+
+        if self.top.as_ref().is_some_and(|top| top.capacity == 0) {
+            return invalid("top capacity must be at least 1".into());
+        }
+
+        if let Some(dnstap) = &self.dnstap {
+            if dnstap.socket.is_some() == dnstap.file.is_some() {
+                return invalid("dnstap needs exactly one of socket and file".into());
+            }
+        }
+
+        if let Some(chinadns) = &self.chinadns {
+            if chinadns.cidr.is_empty() {
+                return invalid("chinadns has no cidr list".into());
+            }
+            groups.push(&chinadns.domestic);
+            groups.push(&chinadns.foreign);
+        }
+
+        for group in groups {
+            match self.upstreams.get(group) {
+                None => return invalid(format!("unknown upstream group {group:?}")),
+                Some(upstream) if upstream.servers.is_empty() => {
+                    return invalid(format!("upstream group {group:?} has no servers"))
+                }
+                Some(upstream) if upstream.wait >= QUERY_TIMEOUT => {
+                    return invalid(format!(
+                        "upstream group {group:?} wait must be below {QUERY_TIMEOUT}ms"
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_parse() {
+        let config: Config = toml::from_str(
+            r#"
+            state_dir = "/var/lib/fakedns"
+
+            [upstreams]
+            direct = { servers = ["223.5.5.5:53"] }
+            corp = { servers = ["10.0.0.53:53"], bogus_ip = ["243.185.187.39"], require_edns = true, wait = 50, rotate = 0 }
+
+            [[rules]]
+            name = "corp"
+            domains = ["corp.example.com"]
+            action = { forward = "corp" }
+
+            [[rules]]
+            name = "ads"
+            file = "ads.conf"
+            action = { block = "nxdomain" }
+
+            [[rules]]
+            name = "nas"
+            domains = ["nas.lan"]
+            action = { static = [{ type = "A", value = "192.168.1.2" }] }
+
+            [[rules]]
+            name = "proxy"
+            domains = ["google.com"]
+            action = { fake_ip = "direct" }
+
+            [[rules]]
+            name = "netflix"
+            domains = ["netflix.com"]
+            action = { forward = "direct" }
+            nftset = ["4#inet#fw#proxy4", "6#inet#fw#proxy6"]
+            nodata = ["AAAA"]
+            strip = ["HTTPS", "svcb"]
+            ttl = { min = 300 }
+
+            [[rules]]
+            name = "mirror"
+            domains = ["registry.npmjs.org"]
+            action = { rewrite = { target = "npm.cache.internal", flatten = true } }
+
+            [[rules]]
+            name = "pinned"
+            domains = ["pkg.example.com"]
+            action = { override = { forward = "direct", ips = ["10.0.0.5", "fd00::5"] } }
+
+            [fake_ip]
+            ipv6 = "fd00:18::/96"
+
+            [reverse]
+            socket = "/run/fakedns.sock"
+
+            [chinadns]
+            foreign = "corp"
+            cidr = ["china_ip.conf"]
+
+            [filter]
+            bogus_nxdomain = ["123.125.81.12"]
+            block_mode = "nxdomain"
+
+            [filter.rebind]
+            mode = "refuse"
+            allow = ["corp.example.com"]
+
+            [ttl]
+            min = 30
+            max = 3600
+
+            [query_log]
+            path = "/var/log/fakedns/query.log"
+            format = "json"
+            sample = 10
+            exclude_clients = ["127.0.0.1"]
+
+            [dnstap]
+            socket = "/run/dnstap.sock"
+            forwarder = false
+
+            [http]
+            listen = "127.0.0.1:9153"
+
+            [top]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.default, DIRECT);
+        assert_eq!(
+            config.state_dir.as_deref(),
+            Some(Path::new("/var/lib/fakedns"))
+        );
+        assert_eq!(config.upstreams["corp"].wait, 50);
+        assert_eq!(config.upstreams["corp"].rotate, 0);
+        assert_eq!(config.upstreams["direct"].rotate, 600);
+        assert_eq!(config.rules.len(), 7);
+        assert!(matches!(&config.rules[5].action, Action::Rewrite(r) if r.flatten));
+        assert!(matches!(&config.rules[6].action, Action::Override(o) if o.ips.len() == 2));
+        assert_eq!(config.rules[4].nftset[1].to_string(), "6#inet#fw#proxy6");
+        assert_eq!(config.rules[4].nodata_types().unwrap(), [rtype::AAAA]);
+        assert_eq!(
+            config.rules[4].strip_types().unwrap(),
+            [rtype::HTTPS, rtype::SVCB]
+        );
+        let ttl = config.rules[4].ttl.or(config.ttl);
+        assert_eq!((ttl.clamp(0), ttl.clamp(86400)), (300, 3600));
+        let fake_ip = config.fake_ip.as_ref().unwrap();
+        assert_eq!(fake_ip.ipv4.unwrap().to_string(), "198.18.0.0/15");
+        assert_eq!(fake_ip.ttl, 1);
+        assert_eq!(config.reverse.as_ref().unwrap().capacity, 65536);
+        let query_log = config.query_log.as_ref().unwrap();
+        assert_eq!(query_log.format, QueryLogFormat::Json);
+        assert_eq!((query_log.max_size, query_log.max_files), (64 << 20, 5));
+        assert_eq!(config.http.as_ref().unwrap().listen.port(), 9153);
+        assert_eq!(config.top.as_ref().unwrap().capacity, 1024);
+        let dnstap = config.dnstap.as_ref().unwrap();
+        assert!(dnstap.client && !dnstap.forwarder);
+        assert_eq!(config.filter.block_mode, BlockMode::Nxdomain);
+        let rebind = config.filter.rebind.as_ref().unwrap();
+        assert_eq!(rebind.mode, RebindMode::Refuse);
+        let chinadns = config.chinadns.as_ref().unwrap();
+        assert_eq!(
+            (chinadns.domestic.as_str(), chinadns.foreign.as_str()),
+            (DIRECT, "corp")
+        );
+        assert!(matches!(
+            config.rules[1].action,
+            Action::Block(BlockMode::Nxdomain)
+        ));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn it_work_validate() {
+        let mut config = Config::legacy(Path::new("a"), Path::new("b"), Path::new("c"));
+        config.validate().unwrap();
+
+        config.rules[2].action = Action::FakeIp(PROXY.into());
+        assert!(config.validate().is_err());
+        config.fake_ip = Some(FakeIpConfig::default());
+        config.validate().unwrap();
+
+        let mut chinadns = ChinaDnsConfig::legacy(Path::new("d"));
+        chinadns.foreign = "missing".into();
+        config.chinadns = Some(chinadns);
+        assert!(config.validate().is_err());
+        config.chinadns = Some(ChinaDnsConfig::legacy(Path::new("d")));
+        config.validate().unwrap();
+
+        config.rules[1].strip = vec!["BOGUS".into()];
+        assert!(config.validate().is_err());
+        config.rules[1].strip.clear();
+
+        config.ttl.max = Some(60);
+        config.rules[1].ttl.min = Some(300);
+        assert!(config.validate().is_err());
+        config.rules[1].ttl.max = Some(600);
+        config.validate().unwrap();
+
+        config.rules[0].action = Action::Forward("missing".into());
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! 上游 UDP 连接：网络变化（源地址失效）后重新连接，并可定期更换源端口
+use std::{
+    future::pending,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::watch,
+    time::{sleep, sleep_until, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// 换用新 socket 后，旧 socket 继续接收已发出请求的应答的时间
+const DRAIN: Duration = Duration::from_secs(2);
+
+/// 切换网络、接口下线等导致的错误，重新绑定并连接后可以恢复
+fn is_network_change(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::AddrNotAvailable
+            | ErrorKind::NetworkDown
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::HostUnreachable
+    )
+}
+
+/// 绑定随机源端口并连接，只接收来自 `remote` 的数据
+async fn connect(remote: SocketAddr) -> Result<UdpSocket> {
+    let bind = match remote {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(remote).await?;
+    debug!(server = %remote, local = ?socket.local_addr().ok(), "dns connect");
+    Ok(socket)
+}
+
+#[derive(Debug)]
+pub struct Conn {
+    remote: SocketAddr,
+    socket: watch::Sender<Arc<UdpSocket>>,
+    /// 测试用：下一次发送直接返回的错误
+    #[cfg(test)]
+    fail_send: std::sync::Mutex<Option<ErrorKind>>,
+}
+
+impl Conn {
+    pub async fn new(remote: SocketAddr) -> Result<Self> {
+        let socket = connect(remote).await?;
+        Ok(Self {
+            remote,
+            socket: watch::Sender::new(Arc::new(socket)),
+            #[cfg(test)]
+            fail_send: Default::default(),
+        })
+    }
+
+    /// 让下一次发送失败，模拟网络变化
+    #[cfg(test)]
+    pub fn fail_next_send(&self, kind: ErrorKind) {
+        *self.fail_send.lock().expect("[E] conn fail_send lock") = Some(kind);
+    }
+
+    async fn send_on(&self, socket: &UdpSocket, buf: &[u8]) -> Result<usize> {
+        #[cfg(test)]
+        if let Some(kind) = self
+            .fail_send
+            .lock()
+            .expect("[E] conn fail_send lock")
+            .take()
+        {
+            return Err(kind.into());
+        }
+        socket.send(buf).await
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.borrow().local_addr().ok()
+    }
+
+    /// 换用新的 socket，旧 socket 由 `Incoming` 读完剩余应答后释放
+    pub async fn reconnect(&self) -> Result<()> {
+        let socket = connect(self.remote).await?;
+        self.socket.send_replace(Arc::new(socket));
+        Ok(())
+    }
+
+    /// 错误由网络变化引起时重新连接，返回是否已恢复
+    pub async fn recover(&self, e: &Error) -> bool {
+        if !is_network_change(e) {
+            return false;
+        }
+        match self.reconnect().await {
+            Ok(()) => {
+                info!(server = %self.remote, local = ?self.local_addr(), error = ?e, "dns reconnected");
+                true
+            }
+            Err(e) => {
+                warn!(server = %self.remote, error = ?e, "dns reconnect");
+                false
+            }
+        }
+    }
+
+    /// 发送失败且重新连接成功时重发一次
+    pub async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let socket = self.socket.borrow().clone();
+        match self.send_on(&socket, buf).await {
+            Err(e) if self.recover(&e).await => {
+                let socket = self.socket.borrow().clone();
+                socket.send(buf).await
+            }
+            sent => sent,
+        }
+    }
+
+    /// 每隔 `every` 更换一次源端口，不返回
+    pub async fn rotate(&self, every: Duration) {
+        loop {
+            sleep(every).await;
+            match self.reconnect().await {
+                Ok(()) => {
+                    debug!(server = %self.remote, local = ?self.local_addr(), "dns source port rotated")
+                }
+                Err(e) => warn!(server = %self.remote, error = ?e, "dns source port rotate"),
+            }
+        }
+    }
+
+    pub fn incoming(&self) -> Incoming {
+        let mut sockets = self.socket.subscribe();
+        let current = sockets.borrow_and_update().clone();
+        Incoming {
+            sockets,
+            current,
+            retiring: None,
+        }
+    }
+}
+
+/// 接收应答，跟随 `Conn` 换用的 socket
+pub struct Incoming {
+    sockets: watch::Receiver<Arc<UdpSocket>>,
+    current: Arc<UdpSocket>,
+    /// 上一个 socket 及其停止接收的时间
+    retiring: Option<(Arc<UdpSocket>, Instant)>,
+}
+
+enum Event {
+    Switched,
+    Ready(Arc<UdpSocket>),
+    Retired,
+}
+
+/// 旧 socket 可读时返回它，到停止接收的时间返回 `None`；没有旧 socket 时一直等待
+async fn retiring(retiring: Option<(Arc<UdpSocket>, Instant)>) -> Result<Option<Arc<UdpSocket>>> {
+    let Some((old, until)) = retiring else {
+        return pending().await;
+    };
+    select! {
+        ready = old.readable() => ready.map(|()| Some(old.clone())),
+        _ = sleep_until(until) => Ok(None),
+    }
+}
+
+impl Incoming {
+    /// `Conn` 已释放时返回 `NotConnected`
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let event = select! {
+                changed = self.sockets.changed() => {
+                    changed.map_err(|_| Error::new(ErrorKind::NotConnected, "dns connection closed"))?;
+                    Event::Switched
+                }
+                ready = self.current.readable() => {
+                    ready?;
+                    Event::Ready(self.current.clone())
+                }
+                ready = retiring(self.retiring.clone()) => match ready? {
+                    Some(old) => Event::Ready(old),
+                    None => Event::Retired,
+                },
+            };
+
+            match event {
+                Event::Switched => {
+                    let socket = self.sockets.borrow_and_update().clone();
+                    let old = std::mem::replace(&mut self.current, socket);
+                    self.retiring = Some((old, Instant::now() + DRAIN));
+                }
+                Event::Retired => self.retiring = None,
+                Event::Ready(socket) => match socket.try_recv(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    received => return received,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 原样发回，返回来源端口
+    async fn echo(upstream: &UdpSocket) -> u16 {
+        let mut buf = [0; 64];
+        let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+        upstream.send_to(&buf[..len], from).await.unwrap();
+        from.port()
+    }
+
+    #[tokio::test]
+    async fn it_work_reconnect() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let conn = Conn::new(upstream.local_addr().unwrap()).await.unwrap();
+        let mut incoming = conn.incoming();
+        let mut buf = [0; 64];
+        let port = |conn: &Conn| conn.local_addr().unwrap().port();
+
+        conn.send(b"first").await.unwrap();
+        let first = echo(&upstream).await;
+        assert_eq!(incoming.recv(&mut buf).await.unwrap(), 5);
+        assert_eq!(first, port(&conn));
+
+        // 与网络变化无关的错误不重新连接
+        assert!(!conn.recover(&ErrorKind::ConnectionRefused.into()).await);
+        assert_eq!(first, port(&conn));
+
+        // 模拟接口下线：源地址失效后换用新的 socket
+        conn.send(b"in flight").await.unwrap();
+        assert!(conn.recover(&ErrorKind::AddrNotAvailable.into()).await);
+        let second = port(&conn);
+        assert_ne!(first, second);
+
+        // 切换前发出的请求，应答仍回到旧端口
+        assert_eq!(echo(&upstream).await, first);
+        assert_eq!(incoming.recv(&mut buf).await.unwrap(), 9);
+        assert_eq!(&buf[..9], b"in flight");
+
+        conn.send(b"after").await.unwrap();
+        assert_eq!(echo(&upstream).await, second);
+        assert_eq!(incoming.recv(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b"after");
+    }
+}
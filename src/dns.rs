@@ -2,20 +2,34 @@ use std::{
     collections::HashMap,
     io,
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot, Mutex, RwLock},
+    spawn,
+    sync::{mpsc, oneshot, Mutex},
+    time::sleep,
 };
+use tracing::{debug, error, trace, warn};
 
-use crate::{cancel, handle, payload::Payload};
+use crate::{
+    cancel, conn::Conn, dnstap::Dnstap, metrics::ServerMetrics, payload::Payload,
+    poison::PoisonGuard,
+};
+
+type Response = oneshot::Sender<Reply>;
 
-type Response = oneshot::Sender<Payload>;
+/// 上游应答，`cached` 表示来自缓存
+#[derive(Debug)]
+pub struct Reply {
+    pub payload: Payload,
+    pub cached: bool,
+}
 
 const CACHE_TTL: Duration = Duration::from_secs(60);
 const CACHE_MAX_SIZE: usize = 4096;
+/// UDP 应答的最大长度，EDNS 下大的应答也要完整收下才能通过检查
+const MAX_RESPONSE: usize = 65535;
 
 #[derive(Debug)]
 struct CacheEntry {
@@ -23,70 +37,225 @@ struct CacheEntry {
     expires_at: Instant,
 }
 
-fn domain_key(payload: &Payload) -> (u16, Vec<u8>) {
+type CacheKey = (u16, Vec<u8>);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// 缓存键中的域名还原为小写点分形式
+fn key_name(key: &CacheKey) -> String {
+    let mut labels = Vec::new();
+    let mut rest = key.1.as_slice();
+    while let Some((&len, tail)) = rest.split_first() {
+        if len == 0 || tail.len() < len as usize {
+            break;
+        }
+        let (label, tail) = tail.split_at(len as usize);
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        rest = tail;
+    }
+    labels.join(".")
+}
+
+fn domain_key(payload: &Payload) -> CacheKey {
     let (_, offset) = payload.domain();
-    let qtype = if payload.0.len() > offset + 2 {
-        (payload.0[offset + 1] as u16) << 8 | payload.0[offset + 2] as u16
-    } else {
-        0
-    };
-    let domain_bytes = payload.0[12..=offset].to_vec();
-    (qtype, domain_bytes)
+    let domain_bytes = payload.0.get(12..=offset).unwrap_or_default().to_vec();
+    (payload.qtype(), domain_bytes)
+}
+
+/// 上游请求 id 取自系统随机源，不可预测，避免被盲猜伪造应答
+fn random_id() -> u16 {
+    let mut buf = [0u8; 2];
+    loop {
+        let n = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if n == buf.len() as isize {
+            return u16::from_be_bytes(buf);
+        }
+        if n < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            // getrandom 不可用时退回进程启动时随机初始化的哈希
+            use std::hash::{BuildHasher, RandomState};
+            return RandomState::new().hash_one(Instant::now()) as u16;
+        }
+    }
+}
+
+/// 等待上游应答的请求
+#[derive(Debug)]
+struct Pending {
+    resp: Response,
+    key: CacheKey,
+    /// 请求带 EDNS，应答必须带 OPT
+    expect_edns: bool,
+    /// 等待窗口内暂存的首个合法应答
+    candidate: Option<Payload>,
+    sent_at: Instant,
 }
 
 #[derive(Debug)]
 pub enum DnsCommand {
-    Query { payload: Payload, resp: Response },
-    TimedOut { id: u16 },
+    /// `payload` 须先经 `Dns::register` 登记
+    Query {
+        payload: Payload,
+    },
+    TimedOut {
+        id: u16,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Dns {
-    sock: Arc<RwLock<UdpSocket>>,
-    map: Arc<Mutex<HashMap<u16, (Response, (u16, Vec<u8>))>>>,
-    cache: Arc<Mutex<HashMap<(u16, Vec<u8>), CacheEntry>>>,
+    remote: SocketAddr,
+    conn: Arc<Conn>,
+    map: Arc<Mutex<HashMap<u16, Pending>>>,
+    cache: Arc<Mutex<HashMap<CacheKey, CacheEntry>>>,
+    guard: Arc<PoisonGuard>,
+    dnstap: Option<Dnstap>,
+    metrics: Arc<ServerMetrics>,
 }
 
 impl Dns {
-    pub async fn new(remote_addr: &str) -> Self {
-        let sock = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .expect("[E] dns bind 0.0.0.0:0");
-        #[cfg(debug_assertions)]
-        println!("[+] dns bind 0:{}", sock.local_addr().unwrap().port());
-
-        let remote_addr = remote_addr
-            .parse::<SocketAddr>()
-            .expect("[E] dns remote_addr parse");
-        sock.connect(remote_addr)
-            .await
-            .expect("[E] dns connect remote_addr");
-        #[cfg(debug_assertions)]
-        println!("[+] dns connect {remote_addr:?}");
+    pub async fn new(
+        remote_addr: &str,
+        guard: Arc<PoisonGuard>,
+        dnstap: Option<Dnstap>,
+        metrics: Arc<ServerMetrics>,
+    ) -> io::Result<Self> {
+        let remote_addr = remote_addr.parse::<SocketAddr>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("server {remote_addr:?}: {e}"),
+            )
+        })?;
+        let conn = Conn::new(remote_addr).await?;
 
-        Self {
-            sock: Arc::new(RwLock::new(sock)),
+        Ok(Self {
+            remote: remote_addr,
+            conn: Arc::new(conn),
             map: Arc::new(Mutex::new(HashMap::new())),
             cache: Arc::new(Mutex::new(HashMap::new())),
+            guard,
+            dnstap,
+            metrics,
+        })
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn conn(&self) -> &Conn {
+        &self.conn
+    }
+
+    /// 清空缓存，或只删除某个域名的各类型记录，返回删除的条数
+    pub async fn flush_cache(&self, name: Option<&str>) -> usize {
+        let mut cache = self.cache.lock().await;
+        let len = cache.len();
+        match name {
+            Some(name) => cache.retain(|key, _| !key_name(key).eq_ignore_ascii_case(name)),
+            None => cache.clear(),
+        }
+        len - cache.len()
+    }
+
+    /// 未过期的缓存条目：(域名, 类型, 剩余时间)
+    pub async fn cache_entries(&self) -> Vec<(String, u16, Duration)> {
+        let now = Instant::now();
+        self.cache
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, entry)| entry.expires_at > now)
+            .map(|(key, entry)| (key_name(key), key.0, entry.expires_at - now))
+            .collect()
+    }
+
+    /// 未过期的缓存应答及其过期时间（Unix 秒），关闭时保存
+    pub async fn export_cache(&self) -> Vec<(u64, Payload)> {
+        let now = Instant::now();
+        let unix_now = unix_now();
+        self.cache
+            .lock()
+            .await
+            .values()
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| {
+                let remaining = (entry.expires_at - now).as_secs();
+                (unix_now + remaining, entry.payload.clone())
+            })
+            .collect()
+    }
+
+    /// 恢复保存的缓存，跳过已过期的条目，返回恢复的条数
+    pub async fn import_cache(&self, entries: Vec<(u64, Payload)>) -> usize {
+        let now = Instant::now();
+        let unix_now = unix_now();
+        let mut cache = self.cache.lock().await;
+        let mut count = 0;
+        for (expires_at, payload) in entries {
+            if expires_at <= unix_now || cache.len() >= CACHE_MAX_SIZE {
+                continue;
+            }
+            let expires_at = now + Duration::from_secs(expires_at - unix_now);
+            cache.insert(
+                domain_key(&payload),
+                CacheEntry {
+                    payload,
+                    expires_at,
+                },
+            );
+            count += 1;
         }
+        count
+    }
+
+    /// 换成随机且未在等待应答的 id 并在同一临界区内登记，避免并发请求抽到相同的 id
+    pub async fn register(&self, payload: &mut Payload, resp: Response) -> u16 {
+        let key = domain_key(payload);
+        let expect_edns = self.guard.expects_edns(payload);
+        let mut map = self.map.lock().await;
+        let id = loop {
+            let id = random_id();
+            if !map.contains_key(&id) {
+                break id;
+            }
+        };
+        payload.set_id(id);
+        map.insert(
+            id,
+            Pending {
+                resp,
+                key,
+                expect_edns,
+                candidate: None,
+                sent_at: Instant::now(),
+            },
+        );
+        self.metrics
+            .pending
+            .store(map.len() as u64, Ordering::Relaxed);
+        id
     }
 
-    async fn recover_sock(&self, err: io::Error) {
-        if err.kind() == io::ErrorKind::AddrNotAvailable {
-            let mut sock = self.sock.write().await;
-            *sock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-            println!("[+] dns reset due to AddrNotAvailable");
+    /// 移出等待中的请求并直接回复，返回查询方是否还在等待
+    fn settle(map: &mut HashMap<u16, Pending>, id: u16, reply: Reply) -> bool {
+        match map.remove(&id) {
+            Some(pending) => pending.resp.send(reply).is_ok(),
+            None => false,
         }
     }
 
-    async fn hit_cache(&self, key: (u16, Vec<u8>), payload_id: u16) -> Option<Payload> {
+    async fn hit_cache(&self, key: CacheKey, payload_id: u16) -> Option<Payload> {
         let mut cache = self.cache.lock().await;
         if let Some(entry) = cache.get_mut(&key) {
             if entry.expires_at > Instant::now() {
                 entry.expires_at = Instant::now() + CACHE_TTL;
                 let mut cached = entry.payload.clone();
-                cached.0[0] = (payload_id >> 8) as u8;
-                cached.0[1] = (payload_id & 0xFF) as u8;
+                cached.set_id(payload_id);
                 return Some(cached);
             } else {
                 cache.remove(&key);
@@ -95,112 +264,338 @@ impl Dns {
         None
     }
 
-    pub async fn work_cmd(&self, mut rx: mpsc::Receiver<DnsCommand>) {
-        #[cfg(debug_assertions)]
-        println!("[+] dns work cmd");
+    /// 发送端全部释放后返回；接收端由调用方持有，任务重启后继续处理
+    pub async fn work_cmd(&self, rx: &mut mpsc::Receiver<DnsCommand>) {
+        trace!(server = %self.remote, "dns work cmd");
 
         while let Some(cmd) = rx.recv().await {
             match cmd {
                 DnsCommand::TimedOut { id } => {
                     let mut map = self.map.lock().await;
-                    let _ = map.remove(&id);
+                    if map.remove(&id).is_some() {
+                        self.metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+                        self.metrics
+                            .pending
+                            .store(map.len() as u64, Ordering::Relaxed);
+                    }
                 }
-                DnsCommand::Query { mut payload, resp } => {
-                    let key = domain_key(&payload);
-                    if let Some(cached) = self.hit_cache(key.clone(), payload.id()).await {
-                        #[cfg(debug_assertions)]
-                        println!(
-                            "[+] dns cache hit for id {} {}",
-                            payload.id(),
-                            payload
-                                .domain()
-                                .0
-                                .iter()
-                                .map(|l| String::from_utf8_lossy(l))
-                                .collect::<Vec<_>>()
-                                .join(".")
-                        );
-
-                        if let Err(e) = resp.send(cached) {
-                            println!("[E] raw response send {e:?}");
+                DnsCommand::Query { mut payload } => {
+                    let id = payload.id();
+                    if let Some(cached) = self.hit_cache(domain_key(&payload), id).await {
+                        debug!(server = %self.remote, id, "dns cache hit");
+                        self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+
+                        let mut map = self.map.lock().await;
+                        let reply = Reply {
+                            payload: cached,
+                            cached: true,
+                        };
+                        if !Self::settle(&mut map, id, reply) {
+                            debug!(server = %self.remote, "dns response receiver gone");
                         }
+                        self.metrics
+                            .pending
+                            .store(map.len() as u64, Ordering::Relaxed);
                         continue;
                     }
+                    self.metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
 
                     let mut map = self.map.lock().await;
-                    let sock = self.sock.read().await;
-                    handle!(handle!(cancel!(sock.send(&payload.as_ref()), 3), e => {
-                        println!("[E] dns request send cancel {e:?}");
-                        payload.servfail();
-                        if let Err(e) = resp.send(payload) {
-                            println!("[E] raw response send {e:?}");
-                        };
-                        continue;
-                    }), e => {
-                        println!("[E] dns request send {e:?}");
+                    let sent = match cancel!(self.conn.send(payload.as_ref()), 3) {
+                        Ok(Ok(_)) => true,
+                        Ok(Err(e)) => {
+                            error!(server = %self.remote, error = ?e, "dns request send");
+                            false
+                        }
+                        Err(e) => {
+                            error!(server = %self.remote, error = ?e, "dns request send timed out");
+                            false
+                        }
+                    };
+                    if !sent {
+                        self.metrics.send_errors.fetch_add(1, Ordering::Relaxed);
                         payload.servfail();
-                        if let Err(e) = resp.send(payload) {
-                            println!("[E] raw response send {e:?}");
+                        let reply = Reply {
+                            payload,
+                            cached: false,
                         };
-                        self.recover_sock(e).await;
+                        Self::settle(&mut map, id, reply);
+                        self.metrics
+                            .pending
+                            .store(map.len() as u64, Ordering::Relaxed);
                         continue;
-                    });
+                    }
+                    if let Some(dnstap) = &self.dnstap {
+                        if let Some(local) = self.conn.local_addr() {
+                            dnstap.forwarder_query(local, self.remote, &payload);
+                        }
+                    }
 
-                    let _ = map.insert(payload.id(), (resp, key));
+                    // 延迟从实际发出时算起
+                    if let Some(pending) = map.get_mut(&id) {
+                        pending.sent_at = Instant::now();
+                    }
                 }
             }
         }
     }
 
     pub async fn work_response(&self) {
-        #[cfg(debug_assertions)]
-        println!("[+] dns work response");
+        trace!(server = %self.remote, "dns work response");
 
-        let mut buf = vec![0; 1024];
+        let mut incoming = self.conn.incoming();
+        let mut buf = vec![0; MAX_RESPONSE];
         loop {
-            let len = match self.sock.read().await.recv(&mut buf).await {
+            let len = match incoming.recv(&mut buf).await {
                 Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return,
                 Err(e) => {
-                    println!("[E] dns response recv {e:?}");
+                    error!(server = %self.remote, error = ?e, "dns response recv");
+                    self.conn.recover(&e).await;
                     continue;
                 }
             };
 
             let payload = Payload::from(&buf[..len]);
+            let id = payload.id();
+            if let Some(dnstap) = &self.dnstap {
+                if let Some(local) = self.conn.local_addr() {
+                    dnstap.forwarder_response(local, self.remote, &payload);
+                }
+            }
 
-            let sender = {
-                let mut map = self.map.lock().await;
-                match map.remove(&payload.id()) {
-                    Some((sender, key)) => {
-                        let mut cache = self.cache.lock().await;
-                        if cache.len() >= CACHE_MAX_SIZE {
-                            if let Some(oldest_key) = cache
-                                .iter()
-                                .min_by_key(|(_, e)| e.expires_at)
-                                .map(|(k, _)| k.clone())
-                            {
-                                cache.remove(&oldest_key);
-                            }
-                        }
-                        cache.insert(
-                            key,
-                            CacheEntry {
-                                payload: payload.clone(),
-                                expires_at: Instant::now() + CACHE_TTL,
-                            },
-                        );
-                        sender
-                    }
-                    None => {
-                        println!("[E] raw response id {} not found", payload.id());
-                        continue;
+            let mut map = self.map.lock().await;
+            let Some(pending) = map.get_mut(&id) else {
+                debug!(server = %self.remote, id, "dns response without pending query");
+                self.metrics
+                    .dropped_unmatched
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+
+            // 未通过检查的应答直接丢弃，继续等待同一 id 的后续应答
+            if let Err(reason) = self.guard.check(pending.expect_edns, &payload) {
+                warn!(server = %self.remote, id, reason, "dns drop response");
+                self.metrics
+                    .dropped_poisoned
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            if self.guard.wait.is_zero() || pending.candidate.is_some() {
+                if pending.candidate.is_some() {
+                    debug!(server = %self.remote, id, "dns prefer later response");
+                }
+                let Some(pending) = map.remove(&id) else {
+                    continue;
+                };
+                self.metrics
+                    .pending
+                    .store(map.len() as u64, Ordering::Relaxed);
+                drop(map);
+                self.deliver(pending, payload).await;
+                continue;
+            }
+
+            // 注入的应答往往先到，暂存首个应答并等待窗口结束
+            pending.candidate = Some(payload);
+            drop(map);
+            let dns = self.clone();
+            spawn(async move {
+                sleep(dns.guard.wait).await;
+                let pending = {
+                    let mut map = dns.map.lock().await;
+                    let pending = map.remove(&id);
+                    dns.metrics
+                        .pending
+                        .store(map.len() as u64, Ordering::Relaxed);
+                    pending
+                };
+                if let Some(mut pending) = pending {
+                    if let Some(payload) = pending.candidate.take() {
+                        dns.deliver(pending, payload).await;
                     }
                 }
-            };
+            });
+        }
+    }
 
-            if let Err(e) = sender.send(payload) {
-                println!("[E] raw response send {e:?}");
+    /// 写入缓存并交给等待的请求
+    async fn deliver(&self, pending: Pending, payload: Payload) {
+        self.metrics.latency.observe(pending.sent_at.elapsed());
+        {
+            let mut cache = self.cache.lock().await;
+            if cache.len() >= CACHE_MAX_SIZE {
+                if let Some(oldest_key) = cache
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires_at)
+                    .map(|(k, _)| k.clone())
+                {
+                    cache.remove(&oldest_key);
+                    self.metrics.cache_evictions.fetch_add(1, Ordering::Relaxed);
+                }
             }
+            cache.insert(
+                pending.key,
+                CacheEntry {
+                    payload: payload.clone(),
+                    expires_at: Instant::now() + CACHE_TTL,
+                },
+            );
+        }
+
+        // 查询方已超时放弃
+        let reply = Reply {
+            payload,
+            cached: false,
+        };
+        if pending.resp.send(reply).is_err() {
+            debug!(server = %self.remote, "dns response receiver gone");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::UpstreamConfig,
+        message::{rtype, Message, RData, Record},
+    };
+    use tokio::{net::UdpSocket, task::JoinHandle};
+
+    fn query(name: &str) -> Payload {
+        Payload::try_from(&Message::query(name, rtype::A)).unwrap()
+    }
+
+    /// 原样发回，返回来源端口
+    async fn echo(upstream: &UdpSocket) -> u16 {
+        let mut buf = [0; 512];
+        let (len, from) =
+            tokio::time::timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+                .await
+                .expect("[E] upstream recv timeout")
+                .unwrap();
+        upstream.send_to(&buf[..len], from).await.unwrap();
+        from.port()
+    }
+
+    async fn ask(
+        dns: &Dns,
+        tx: &mpsc::Sender<DnsCommand>,
+        mut payload: Payload,
+    ) -> (u16, oneshot::Receiver<Reply>) {
+        let (resp, rx) = oneshot::channel();
+        let id = dns.register(&mut payload, resp).await;
+        tx.send(DnsCommand::Query { payload }).await.unwrap();
+        (id, rx)
+    }
+
+    /// 本地上游与运行中的 `Dns`，返回的任务由调用方结束
+    async fn start(
+        guard: PoisonGuard,
+    ) -> (
+        UdpSocket,
+        Dns,
+        mpsc::Sender<DnsCommand>,
+        [JoinHandle<()>; 2],
+    ) {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+        let dns = Dns::new(&addr, Arc::new(guard), None, Default::default())
+            .await
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let worker = dns.clone();
+        let cmd = spawn(async move { worker.work_cmd(&mut rx).await });
+        let worker = dns.clone();
+        let response = spawn(async move { worker.work_response().await });
+        (upstream, dns, tx, [cmd, response])
+    }
+
+    #[tokio::test]
+    async fn it_work_reconnect_on_send_error() {
+        let (upstream, dns, tx, [cmd, response]) = start(Default::default()).await;
+
+        let (id, reply) = ask(&dns, &tx, query("a.example.com")).await;
+        let first = echo(&upstream).await;
+        assert_eq!(reply.await.unwrap().payload.id(), id);
+
+        // 发送时源地址失效：重新连接后用新的 socket 重发
+        dns.conn().fail_next_send(io::ErrorKind::AddrNotAvailable);
+        let (id, reply) = ask(&dns, &tx, query("b.example.com")).await;
+        let second = echo(&upstream).await;
+        assert_ne!(first, second);
+        assert_eq!(second, dns.conn().local_addr().unwrap().port());
+        let reply = reply.await.unwrap();
+        assert_eq!(reply.payload.id(), id);
+        assert_eq!(reply.payload.rcode(), 0);
+
+        // 之后的查询继续走新的 socket
+        let (id, reply) = ask(&dns, &tx, query("c.example.com")).await;
+        assert_eq!(echo(&upstream).await, second);
+        assert_eq!(reply.await.unwrap().payload.id(), id);
+
+        cmd.abort();
+        response.abort();
+    }
+
+    #[tokio::test]
+    async fn it_work_register_unique_id() {
+        let (_upstream, dns, _tx, tasks) = start(Default::default()).await;
+        // 数量远超生日界，id 不重新抽取时必然有登记被覆盖
+        let mut receivers = Vec::new();
+        for _ in 0..2000 {
+            let (resp, rx) = oneshot::channel();
+            let mut payload = query("a.example.com");
+            dns.register(&mut payload, resp).await;
+            receivers.push(rx);
         }
+        assert_eq!(dns.map.lock().await.len(), 2000);
+        // 被覆盖的登记会释放发送端
+        assert!(receivers
+            .iter_mut()
+            .all(|rx| matches!(rx.try_recv(), Err(oneshot::error::TryRecvError::Empty))));
+
+        tasks.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test]
+    async fn it_work_large_response() {
+        let guard = PoisonGuard::from(&UpstreamConfig {
+            require_edns: true,
+            ..Default::default()
+        });
+        let (upstream, dns, tx, tasks) = start(guard).await;
+
+        let mut message = Message::try_from(&query("txt.example.com")).unwrap();
+        let opt = Record::with_type("", rtype::OPT, 0, RData::Raw(Vec::new()));
+        message.additionals.push(opt.clone());
+        let (_, reply) = ask(&dns, &tx, Payload::try_from(&message).unwrap()).await;
+
+        // 超过 1024 字节的 EDNS 应答完整收下，能通过检查
+        let mut buf = [0; 512];
+        let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+        let mut response = Message::reply(&Message::try_from(&buf[..len]).unwrap());
+        for _ in 0..8 {
+            response.answers.push(Record::with_type(
+                "txt.example.com",
+                rtype::TXT,
+                60,
+                RData::Txt(vec![vec![b'x'; 200]]),
+            ));
+        }
+        response.additionals = vec![opt];
+        let response = response.encode().unwrap();
+        assert!(response.len() > 1024);
+        upstream.send_to(&response, from).await.unwrap();
+
+        let reply = tokio::time::timeout(Duration::from_secs(2), reply)
+            .await
+            .expect("[E] large response dropped")
+            .unwrap();
+        assert_eq!(reply.payload.as_ref(), &response[..]);
+
+        tasks.iter().for_each(JoinHandle::abort);
     }
 }
@@ -0,0 +1,475 @@
+//! dnstap 输出：客户端与上游的查询、应答以 protobuf 编码，经 Frame Streams 写入文件或 Unix socket
+//!
+//! 协议见 <https://dnstap.info> 与 fstrm 的 Frame Streams 规范。socket 模式使用双向握手
+//! READY/ACCEPT/START，断开后定期重连，期间的消息直接丢弃。
+use std::{
+    fs::File,
+    io::{BufWriter, Error, ErrorKind, Read, Result, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::spawn_blocking};
+use tracing::{error, info, warn};
+
+use crate::{config::DnstapConfig, payload::Payload};
+
+/// 待写入的消息数上限，写入跟不上时丢弃新的消息而不阻塞应答
+const MAX_PENDING: usize = 8192;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const VERSION: &str = concat!("fakedns ", env!("CARGO_PKG_VERSION"));
+
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+/// 控制帧长度上限，防止对端发送异常长度
+const MAX_CONTROL_FRAME: usize = 512;
+
+/// dnstap.Message.Type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+#[derive(Debug)]
+struct Event {
+    kind: Kind,
+    query_addr: SocketAddr,
+    response_addr: SocketAddr,
+    query_time: Option<SystemTime>,
+    response_time: Option<SystemTime>,
+    message: Payload,
+}
+
+/// protobuf 编码，只用到 varint、fixed32 与 length-delimited 三种类型
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn uint(&mut self, field: u64, v: u64) {
+        self.varint(field << 3);
+        self.varint(v);
+    }
+
+    fn fixed32(&mut self, field: u64, v: u32) {
+        self.varint(field << 3 | 5);
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, v: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+
+    fn time(&mut self, sec_field: u64, time: SystemTime) {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.uint(sec_field, since.as_secs());
+        self.fixed32(sec_field + 1, since.subsec_nanos());
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+impl Event {
+    /// 编码为 dnstap.Dnstap
+    fn encode(&self, identity: Option<&[u8]>) -> Vec<u8> {
+        let mut message = Proto::default();
+        message.uint(1, self.kind as u64);
+        // SocketFamily INET / INET6，SocketProtocol UDP
+        let family = if self.query_addr.is_ipv4() { 1 } else { 2 };
+        message.uint(2, family);
+        message.uint(3, 1);
+        message.bytes(4, &ip_bytes(self.query_addr.ip()));
+        message.bytes(5, &ip_bytes(self.response_addr.ip()));
+        message.uint(6, self.query_addr.port() as u64);
+        message.uint(7, self.response_addr.port() as u64);
+        if let Some(time) = self.query_time {
+            message.time(8, time);
+        }
+        match self.kind {
+            Kind::ClientQuery | Kind::ForwarderQuery => message.bytes(10, self.message.as_ref()),
+            Kind::ClientResponse | Kind::ForwarderResponse => {}
+        }
+        if let Some(time) = self.response_time {
+            message.time(12, time);
+        }
+        match self.kind {
+            Kind::ClientResponse | Kind::ForwarderResponse => {
+                message.bytes(14, self.message.as_ref())
+            }
+            Kind::ClientQuery | Kind::ForwarderQuery => {}
+        }
+
+        let mut dnstap = Proto::default();
+        if let Some(identity) = identity {
+            dnstap.bytes(1, identity);
+        }
+        dnstap.bytes(2, VERSION.as_bytes());
+        dnstap.bytes(14, &message.0);
+        // Dnstap.Type MESSAGE
+        dnstap.uint(15, 1);
+        dnstap.0
+    }
+}
+
+/// 控制帧：转义的 0 长度、帧长度、类型，以及可选的 content type 字段
+fn control_frame(control: u32, content_type: bool) -> Vec<u8> {
+    let mut body = control.to_be_bytes().to_vec();
+    if content_type {
+        body.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+fn read_control_frame(stream: &mut impl Read) -> Result<u32> {
+    let mut word = [0; 4];
+    stream.read_exact(&mut word)?;
+    if word != [0; 4] {
+        return Err(Error::new(ErrorKind::InvalidData, "expect control frame"));
+    }
+    stream.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if !(4..=MAX_CONTROL_FRAME).contains(&len) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "invalid control frame length",
+        ));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok(u32::from_be_bytes([body[0], body[1], body[2], body[3]]))
+}
+
+enum Sink {
+    File(BufWriter<File>),
+    Socket {
+        path: PathBuf,
+        stream: Option<BufWriter<UnixStream>>,
+        retry_at: Instant,
+    },
+}
+
+impl Sink {
+    fn open(config: &DnstapConfig) -> Result<Self> {
+        match (&config.file, &config.socket) {
+            (Some(file), _) => {
+                let mut file = BufWriter::new(File::create(file)?);
+                file.write_all(&control_frame(CONTROL_START, true))?;
+                Ok(Sink::File(file))
+            }
+            (None, Some(path)) => Ok(Sink::Socket {
+                path: path.clone(),
+                stream: None,
+                retry_at: Instant::now(),
+            }),
+            (None, None) => Err(Error::new(ErrorKind::InvalidInput, "dnstap without output")),
+        }
+    }
+
+    fn connect(path: &PathBuf) -> Result<BufWriter<UnixStream>> {
+        let mut stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        stream.write_all(&control_frame(CONTROL_READY, true))?;
+        if read_control_frame(&mut stream)? != CONTROL_ACCEPT {
+            return Err(Error::new(ErrorKind::InvalidData, "expect ACCEPT"));
+        }
+        stream.write_all(&control_frame(CONTROL_START, true))?;
+        Ok(BufWriter::new(stream))
+    }
+
+    /// 当前可写的输出，socket 断开时按间隔重连
+    fn writer(&mut self) -> Option<&mut dyn Write> {
+        match self {
+            Sink::File(file) => Some(file),
+            Sink::Socket {
+                path,
+                stream,
+                retry_at,
+            } => {
+                if stream.is_none() && Instant::now() >= *retry_at {
+                    match Self::connect(path) {
+                        Ok(s) => {
+                            info!(socket = ?path, "dnstap connected");
+                            *stream = Some(s);
+                        }
+                        Err(e) => {
+                            warn!(socket = ?path, error = ?e, "dnstap connect");
+                            *retry_at = Instant::now() + RECONNECT_INTERVAL;
+                        }
+                    }
+                }
+                stream.as_mut().map(|s| s as &mut dyn Write)
+            }
+        }
+    }
+
+    fn write_frame(&mut self, data: &[u8]) -> Result<()> {
+        let Some(writer) = self.writer() else {
+            return Ok(());
+        };
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Socket { stream, .. } => stream.as_mut().map_or(Ok(()), |s| s.flush()),
+        }
+    }
+
+    /// 写入出错后丢弃连接，等待重连
+    fn reset(&mut self) {
+        if let Sink::Socket {
+            stream, retry_at, ..
+        } = self
+        {
+            *stream = None;
+            *retry_at = Instant::now() + RECONNECT_INTERVAL;
+        }
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer() {
+            writer.write_all(&control_frame(CONTROL_STOP, false))?;
+        }
+        self.flush()
+    }
+}
+
+/// 消息经由通道交给独立线程编码写入，`None` 通知写入线程结束
+#[derive(Debug, Clone)]
+pub struct Dnstap {
+    tx: mpsc::Sender<Option<Event>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    client: bool,
+    forwarder: bool,
+}
+
+impl TryFrom<&DnstapConfig> for Dnstap {
+    type Error = Error;
+
+    fn try_from(config: &DnstapConfig) -> Result<Self> {
+        let mut sink = Sink::open(config)?;
+        let identity = config.identity.clone().map(String::into_bytes);
+
+        let (tx, mut rx) = mpsc::channel::<Option<Event>>(MAX_PENDING);
+        let writer = thread::Builder::new()
+            .name("dnstap".into())
+            .spawn(move || {
+                let mut closed = false;
+                while let Some(Some(event)) = rx.blocking_recv() {
+                    let mut next = Some(event);
+                    let mut result = Ok(());
+                    while let Some(event) = next {
+                        if result.is_ok() {
+                            result = sink.write_frame(&event.encode(identity.as_deref()));
+                        }
+                        next = match rx.try_recv() {
+                            Ok(Some(event)) => Some(event),
+                            Ok(None) => {
+                                closed = true;
+                                None
+                            }
+                            Err(_) => None,
+                        };
+                    }
+                    if let Err(e) = result.and_then(|_| sink.flush()) {
+                        error!(error = ?e, "dnstap write");
+                        sink.reset();
+                    }
+                    if closed {
+                        break;
+                    }
+                }
+                // 关闭或所有发送端释放后结束流
+                if let Err(e) = sink.stop() {
+                    error!(error = ?e, "dnstap stop");
+                }
+            })?;
+
+        Ok(Self {
+            tx,
+            writer: Arc::new(Mutex::new(Some(writer))),
+            client: config.client,
+            forwarder: config.forwarder,
+        })
+    }
+}
+
+impl Dnstap {
+    fn send(&self, event: Event) {
+        // 输出不可用时丢弃，不影响应答
+        let _ = self.tx.try_send(Some(event));
+    }
+
+    /// 写完已排队的消息后结束流，等待写入线程退出
+    pub async fn close(&self) {
+        if self.tx.send(None).await.is_err() {
+            return;
+        }
+        let writer = self.writer.lock().expect("[E] dnstap writer lock").take();
+        if let Some(writer) = writer {
+            let _ = spawn_blocking(move || writer.join()).await;
+        }
+    }
+
+    pub fn client_query(&self, client: SocketAddr, local: SocketAddr, query: &Payload) {
+        if self.client {
+            self.send(Event {
+                kind: Kind::ClientQuery,
+                query_addr: client,
+                response_addr: local,
+                query_time: Some(SystemTime::now()),
+                response_time: None,
+                message: query.clone(),
+            });
+        }
+    }
+
+    pub fn client_response(
+        &self,
+        client: SocketAddr,
+        local: SocketAddr,
+        query_time: SystemTime,
+        response: &Payload,
+    ) {
+        if self.client {
+            self.send(Event {
+                kind: Kind::ClientResponse,
+                query_addr: client,
+                response_addr: local,
+                query_time: Some(query_time),
+                response_time: Some(SystemTime::now()),
+                message: response.clone(),
+            });
+        }
+    }
+
+    pub fn forwarder_query(&self, local: SocketAddr, server: SocketAddr, query: &Payload) {
+        if self.forwarder {
+            self.send(Event {
+                kind: Kind::ForwarderQuery,
+                query_addr: local,
+                response_addr: server,
+                query_time: Some(SystemTime::now()),
+                response_time: None,
+                message: query.clone(),
+            });
+        }
+    }
+
+    pub fn forwarder_response(&self, local: SocketAddr, server: SocketAddr, response: &Payload) {
+        if self.forwarder {
+            self.send(Event {
+                kind: Kind::ForwarderResponse,
+                query_addr: local,
+                response_addr: server,
+                query_time: None,
+                response_time: Some(SystemTime::now()),
+                message: response.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn it_work_dnstap() {
+        let event = Event {
+            kind: Kind::ForwarderQuery,
+            query_addr: "10.0.0.1:40000".parse().unwrap(),
+            response_addr: "8.8.8.8:53".parse().unwrap(),
+            query_time: Some(UNIX_EPOCH + Duration::new(300, 7)),
+            response_time: None,
+            message: Payload(vec![0xab, 0xcd]),
+        };
+        let encoded = event.encode(Some(b"gw"));
+        // identity、version
+        assert_eq!(&encoded[..4], [0x0a, 2, b'g', b'w']);
+        assert_eq!(encoded[4], 0x12);
+        let message_at = 6 + VERSION.len();
+        assert_eq!(encoded[message_at], 0x72);
+        let message = &encoded[message_at + 2..encoded.len() - 2];
+        assert_eq!(encoded[message_at + 1] as usize, message.len());
+        assert_eq!(&encoded[encoded.len() - 2..], [0x78, 1]);
+        assert_eq!(
+            message,
+            [
+                0x08, 7, 0x10, 1, 0x18, 1, 0x22, 4, 10, 0, 0, 1, 0x2a, 4, 8, 8, 8, 8, 0x30, 0xc0,
+                0xb8, 0x02, 0x38, 53, 0x40, 0xac, 0x02, 0x4d, 7, 0, 0, 0, 0x52, 2, 0xab, 0xcd
+            ]
+        );
+
+        // socket 模式的握手：READY → ACCEPT → START → 数据帧 → STOP
+        let dir = std::env::temp_dir().join(format!("fakedns-dnstap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_READY);
+            stream
+                .write_all(&control_frame(CONTROL_ACCEPT, true))
+                .unwrap();
+            assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_START);
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut frame).unwrap();
+            assert_eq!(read_control_frame(&mut stream).unwrap(), CONTROL_STOP);
+            frame
+        });
+
+        let config = DnstapConfig {
+            socket: Some(path),
+            file: None,
+            identity: None,
+            client: true,
+            forwarder: false,
+        };
+        let dnstap = Dnstap::try_from(&config).unwrap();
+        let client = "192.168.1.10:5353".parse().unwrap();
+        let local = "0.0.0.0:53".parse().unwrap();
+        // forwarder 关闭时不输出
+        dnstap.forwarder_query(local, client, &Payload(vec![1]));
+        dnstap.client_query(client, local, &Payload(vec![0x12, 0x34]));
+        drop(dnstap);
+
+        let frame = collector.join().unwrap();
+        assert!(frame.ends_with(&[0x52, 2, 0x12, 0x34, 0x78, 1]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    fs::{read_to_string, rename, File},
+    io::{BufWriter, ErrorKind, Result, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cidr::Cidr,
+    config::FakeIpConfig,
+    local::parse_reverse_name,
+    message::{rtype, Message, RData, Record},
+};
+
+/// 分配时最多探测的地址数，找不到空闲地址则回收其中最早过期的
+const MAX_PROBE: u128 = 64;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub domain: String,
+    /// unix 时间戳（秒）
+    pub expires_at: u64,
+}
+
+impl Mapping {
+    /// 剩余有效秒数
+    pub fn ttl(&self) -> u64 {
+        self.expires_at.saturating_sub(now())
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    by_ip: HashMap<IpAddr, Mapping>,
+    by_domain: HashMap<(String, bool), IpAddr>,
+    cursor: [u128; 2],
+}
+
+impl Table {
+    fn remove_ip(&mut self, ip: IpAddr) {
+        if let Some(old) = self.by_ip.remove(&ip) {
+            self.by_domain.remove(&(old.domain, ip.is_ipv6()));
+        }
+    }
+
+    fn insert(&mut self, ip: IpAddr, mapping: Mapping) {
+        self.remove_ip(ip);
+        self.by_domain
+            .insert((mapping.domain.clone(), ip.is_ipv6()), ip);
+        self.by_ip.insert(ip, mapping);
+    }
+}
+
+/// 为域名分配地址池中的虚假地址，并维护双向映射
+pub struct FakeIp {
+    v4: Option<Cidr>,
+    v6: Option<Cidr>,
+    ttl: u32,
+    expire: u64,
+    persist: Option<PathBuf>,
+    table: Mutex<Table>,
+}
+
+impl From<&FakeIpConfig> for FakeIp {
+    fn from(config: &FakeIpConfig) -> Self {
+        Self {
+            v4: config.ipv4,
+            v6: config.ipv6,
+            ttl: config.ttl,
+            expire: config.expire,
+            persist: config.persist.clone(),
+            table: Mutex::new(Table::default()),
+        }
+    }
+}
+
+impl FakeIp {
+    fn pool(&self, v6: bool) -> Option<Cidr> {
+        if v6 {
+            self.v6
+        } else {
+            self.v4
+        }
+    }
+
+    /// 可分配的偏移范围，IPv4 跳过网络地址与广播地址
+    fn usable(pool: Cidr) -> (u128, u128) {
+        let size = pool.size();
+        match (pool.addr.is_ipv4(), size) {
+            (true, size) if size > 2 => (1, size - 2),
+            (false, size) if size > 1 => (1, size - 1),
+            (_, size) => (0, size),
+        }
+    }
+
+    fn allocate(&self, domain: &str, v6: bool) -> Option<IpAddr> {
+        let pool = self.pool(v6)?;
+        let now = now();
+        let expires_at = now + self.expire;
+        let mut table = self.table.lock().expect("[E] fake-ip lock");
+
+        let key = (domain.to_string(), v6);
+        if let Some(&ip) = table.by_domain.get(&key) {
+            if let Some(mapping) = table.by_ip.get_mut(&ip) {
+                mapping.expires_at = expires_at;
+            }
+            return Some(ip);
+        }
+
+        let (start, count) = Self::usable(pool);
+        let cursor = table.cursor[v6 as usize];
+        let mut chosen = None;
+        let mut oldest: Option<(u64, u128)> = None;
+        for i in 0..MAX_PROBE.min(count) {
+            let pos = (cursor + i) % count;
+            match table.by_ip.get(&pool.nth(start + pos)) {
+                Some(mapping) if mapping.expires_at > now => {
+                    if oldest.is_none_or(|(at, _)| mapping.expires_at < at) {
+                        oldest = Some((mapping.expires_at, pos));
+                    }
+                }
+                _ => {
+                    chosen = Some(pos);
+                    break;
+                }
+            }
+        }
+        let pos = chosen.or(oldest.map(|(_, pos)| pos))?;
+        table.cursor[v6 as usize] = (pos + 1) % count;
+        let ip = pool.nth(start + pos);
+
+        table.insert(
+            ip,
+            Mapping {
+                domain: domain.into(),
+                expires_at,
+            },
+        );
+        Some(ip)
+    }
+
+    /// 虚假地址的 PTR 查询以映射的域名应答，供透明代理反查
+    pub fn answer_ptr(&self, query: &Message) -> Option<Message> {
+        let question = query.question()?;
+        if question.qtype != rtype::PTR {
+            return None;
+        }
+        let ip = parse_reverse_name(&question.name.to_ascii_lowercase())
+            .filter(|&ip| self.contains(ip))?;
+
+        let mut reply = Message::reply(query);
+        if let Some(mapping) = self.lookup(ip) {
+            reply.answers.push(Record::with_type(
+                &question.name,
+                rtype::PTR,
+                self.ttl,
+                RData::Name(mapping.domain),
+            ));
+        }
+        Some(reply)
+    }
+
+    /// A/AAAA 查询以虚假地址应答，其它类型返回 None 交由上游处理
+    pub fn answer(&self, query: &Message) -> Option<Message> {
+        let question = query.question()?;
+        let v6 = match question.qtype {
+            rtype::A => false,
+            rtype::AAAA => true,
+            _ => return None,
+        };
+
+        let mut reply = Message::reply(query);
+        if let Some(ip) = self.allocate(&question.name.to_ascii_lowercase(), v6) {
+            let data = match ip {
+                IpAddr::V4(ip) => RData::A(ip),
+                IpAddr::V6(ip) => RData::Aaaa(ip),
+            };
+            reply
+                .answers
+                .push(Record::new(&question.name, self.ttl, data));
+        }
+        Some(reply)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        [self.v4, self.v6]
+            .iter()
+            .flatten()
+            .any(|pool| pool.contains(ip))
+    }
+
+    /// 虚假地址对应的域名
+    pub fn lookup(&self, ip: IpAddr) -> Option<Mapping> {
+        let table = self.table.lock().expect("[E] fake-ip lock");
+        table
+            .by_ip
+            .get(&ip)
+            .filter(|m| m.expires_at > now())
+            .cloned()
+    }
+
+    /// 读取持久化的映射，丢弃已过期或不在地址池内的条目
+    pub fn load(&self) -> Result<usize> {
+        let Some(path) = &self.persist else {
+            return Ok(0);
+        };
+        let content = match read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let now = now();
+        let mut table = self.table.lock().expect("[E] fake-ip lock");
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [ip, domain, expires_at] = fields[..] else {
+                continue;
+            };
+            let (Ok(ip), Ok(expires_at)) = (ip.parse::<IpAddr>(), expires_at.parse::<u64>()) else {
+                continue;
+            };
+            if expires_at > now && self.contains(ip) {
+                table.insert(
+                    ip,
+                    Mapping {
+                        domain: domain.into(),
+                        expires_at,
+                    },
+                );
+            }
+        }
+        Ok(table.by_ip.len())
+    }
+
+    /// 写入持久化文件，先写临时文件再替换
+    pub fn save(&self) -> Result<usize> {
+        let Some(path) = &self.persist else {
+            return Ok(0);
+        };
+        let tmp = path.with_extension("tmp");
+        let mut f = BufWriter::new(File::create(&tmp)?);
+
+        let now = now();
+        let mut count = 0;
+        {
+            let table = self.table.lock().expect("[E] fake-ip lock");
+            for (ip, mapping) in table.by_ip.iter().filter(|(_, m)| m.expires_at > now) {
+                writeln!(f, "{ip} {} {}", mapping.domain, mapping.expires_at)?;
+                count += 1;
+            }
+        }
+        f.flush()?;
+        drop(f);
+        rename(tmp, path)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    fn fake_ip(ipv4: &str, persist: Option<PathBuf>) -> FakeIp {
+        FakeIp::from(&FakeIpConfig {
+            ipv4: Some(ipv4.parse().unwrap()),
+            ipv6: Some("fd00:18::/120".parse().unwrap()),
+            ttl: 1,
+            expire: 60,
+            persist,
+        })
+    }
+
+    #[test]
+    fn it_work_allocate() {
+        let pool = fake_ip("198.18.0.0/30", None);
+        let a = pool.allocate("a.com", false).unwrap();
+        assert_eq!(a, "198.18.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.allocate("a.com", false), Some(a));
+        let b = pool.allocate("b.com", false).unwrap();
+        assert_eq!(b, "198.18.0.2".parse::<IpAddr>().unwrap());
+
+        // 地址池已满时回收最早过期的映射
+        let c = pool.allocate("c.com", false).unwrap();
+        assert_eq!(c, a);
+        assert!(!pool
+            .table
+            .lock()
+            .unwrap()
+            .by_domain
+            .contains_key(&("a.com".into(), false)));
+        assert_eq!(pool.lookup(c).unwrap().domain, "c.com");
+
+        let v6 = pool.allocate("a.com", true).unwrap();
+        assert_eq!(v6, "fd00:18::1".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.lookup(v6).unwrap().domain, "a.com");
+    }
+
+    #[test]
+    fn it_work_persist() {
+        let path = temp_dir().join(format!("fakedns-fakeip-{}.txt", std::process::id()));
+        let pool = fake_ip("198.18.0.0/15", Some(path.clone()));
+        let ip = pool.allocate("example.com", false).unwrap();
+        assert_eq!(pool.save().unwrap(), 1);
+
+        let restored = fake_ip("198.18.0.0/15", Some(path.clone()));
+        assert_eq!(restored.load().unwrap(), 1);
+        assert_eq!(restored.lookup(ip).unwrap().domain, "example.com");
+
+        // 地址池变更后旧条目被丢弃
+        let moved = fake_ip("10.0.0.0/8", Some(path.clone()));
+        assert_eq!(moved.load().unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
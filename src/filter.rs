@@ -0,0 +1,252 @@
+use std::io::{Error, Result};
+use tracing::{debug, error, warn};
+
+use crate::{
+    config::{BlockMode, FilterConfig, RebindConfig, RebindMode},
+    message::{rcode, Message},
+    payload::Payload,
+    resolver::block_response,
+    trie::{DomainTrie, IpTrie, Trie},
+};
+
+/// 公网域名不应解析到的地址
+const PRIVATE_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// DNS rebinding 防护
+struct Rebind {
+    private: IpTrie,
+    allow: DomainTrie,
+    mode: RebindMode,
+}
+
+impl From<&RebindConfig> for Rebind {
+    fn from(config: &RebindConfig) -> Self {
+        let mut private = IpTrie::default();
+        for cidr in PRIVATE_RANGES {
+            private.insert(cidr.parse().expect("[E] private range"));
+        }
+        let mut allow: DomainTrie = Trie::with_capacity(config.allow.len()).into();
+        allow.extend_content("allow", &config.allow.join("\n"));
+
+        Self {
+            private,
+            allow,
+            mode: config.mode,
+        }
+    }
+}
+
+impl Rebind {
+    /// 去掉或拒绝指向内网的应答，返回是否改动
+    fn apply(&self, message: &mut Message) -> bool {
+        let Some(name) = message.question().map(|q| q.name.to_ascii_lowercase()) else {
+            return false;
+        };
+        let reversed: Vec<&str> = name.split('.').rev().collect();
+        if self.allow.domain_match(&reversed).is_some() {
+            return false;
+        }
+
+        let is_private = |ip| self.private.contains(ip);
+        let Some(ip) = message
+            .answer_ips()
+            .map(|(ip, _)| ip)
+            .find(|&ip| is_private(ip))
+        else {
+            return false;
+        };
+        warn!(qname = %name, %ip, mode = ?self.mode, "possible dns rebinding");
+
+        match self.mode {
+            RebindMode::Strip => message.answers.retain(|r| !r.ip().is_some_and(is_private)),
+            RebindMode::Refuse => {
+                message.answers.clear();
+                message.authorities.clear();
+                message.set_rcode(rcode::REFUSED);
+            }
+        }
+        true
+    }
+}
+
+/// 按应答中的地址改写上游应答
+pub struct ResponseFilter {
+    /// 运营商把 NXDOMAIN 改写成的广告地址，命中时还原为 NXDOMAIN
+    bogus_nxdomain: IpTrie,
+    block_ip: IpTrie,
+    block_mode: BlockMode,
+    rebind: Option<Rebind>,
+}
+
+impl TryFrom<&FilterConfig> for ResponseFilter {
+    type Error = Error;
+
+    fn try_from(config: &FilterConfig) -> Result<Self> {
+        let mut bogus_nxdomain = IpTrie::default();
+        for cidr in &config.bogus_nxdomain {
+            bogus_nxdomain.insert(*cidr);
+        }
+
+        let mut block_ip = IpTrie::default();
+        for cidr in &config.block_ip {
+            block_ip.insert(*cidr);
+        }
+        for file in &config.block_ip_file {
+            block_ip
+                .extend_file(file)
+                .map_err(|e| Error::new(e.kind(), format!("filter {file:?}: {e}")))?;
+        }
+        block_ip.shrink_to_fit();
+
+        Ok(Self {
+            bogus_nxdomain,
+            block_ip,
+            block_mode: config.block_mode,
+            rebind: config.rebind.as_ref().map(Rebind::from),
+        })
+    }
+}
+
+impl ResponseFilter {
+    pub fn len(&self) -> usize {
+        self.bogus_nxdomain.len() + self.block_ip.len()
+    }
+
+    /// 未命中时原样返回；rebinding 检查在前，拦截产生的 0.0.0.0 不受影响
+    pub fn apply(&self, response: Payload) -> Payload {
+        let Ok(mut message) = Message::try_from(&response) else {
+            return response;
+        };
+        let rebound = self
+            .rebind
+            .as_ref()
+            .is_some_and(|rebind| rebind.apply(&mut message));
+
+        let reply = if message
+            .answer_ips()
+            .any(|(ip, _)| self.bogus_nxdomain.contains(ip))
+        {
+            let mut reply = Message::reply(&message);
+            reply.set_rcode(rcode::NXDOMAIN);
+            reply
+        } else if message
+            .answer_ips()
+            .any(|(ip, _)| self.block_ip.contains(ip))
+        {
+            match block_response(&message, self.block_mode) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    error!(error = ?e, "filter response");
+                    return response;
+                }
+            }
+        } else if rebound {
+            message
+        } else {
+            return response;
+        };
+
+        debug!(rcode = reply.rcode(), "response filtered");
+        match Payload::try_from(&reply) {
+            Ok(reply) => reply,
+            Err(e) => {
+                error!(error = ?e, "filter response");
+                response
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{rtype, RData, Record};
+    use std::net::Ipv4Addr;
+
+    fn response(ip: &str) -> Payload {
+        let mut message = Message::reply(&Message::query("typo.example", rtype::A));
+        message.answers.push(Record::new(
+            "typo.example",
+            60,
+            RData::A(ip.parse().unwrap()),
+        ));
+        Payload::try_from(&message).unwrap()
+    }
+
+    #[test]
+    fn it_work_filter() {
+        let filter = ResponseFilter::try_from(&FilterConfig {
+            bogus_nxdomain: vec!["123.125.81.12".parse().unwrap()],
+            block_ip: vec!["10.0.0.0/8".parse().unwrap()],
+            block_ip_file: Vec::new(),
+            block_mode: BlockMode::ZeroIp,
+            rebind: None,
+        })
+        .unwrap();
+        assert_eq!(filter.len(), 2);
+
+        let clean = response("93.184.216.34");
+        assert_eq!(filter.apply(clean.clone()).0, clean.0);
+
+        let nx = Message::try_from(&filter.apply(response("123.125.81.12"))).unwrap();
+        assert_eq!((nx.id, nx.rcode()), (7, rcode::NXDOMAIN));
+        assert!(nx.answers.is_empty());
+
+        let blocked = Message::try_from(&filter.apply(response("10.1.2.3"))).unwrap();
+        assert_eq!(blocked.answers[0].data, RData::A(Ipv4Addr::UNSPECIFIED));
+    }
+
+    #[test]
+    fn it_work_rebind() {
+        let mut config = FilterConfig {
+            block_ip: vec!["10.0.0.0/8".parse().unwrap()],
+            rebind: Some(RebindConfig {
+                mode: RebindMode::Strip,
+                allow: vec!["example".into()],
+            }),
+            ..Default::default()
+        };
+        let filter = ResponseFilter::try_from(&config).unwrap();
+
+        // 白名单内的域名不处理，拦截地址仍按 block_mode 改写为 0.0.0.0
+        let clean = response("192.168.1.1");
+        assert_eq!(filter.apply(clean.clone()).0, clean.0);
+        let blocked = Message::try_from(&filter.apply(response("10.1.2.3"))).unwrap();
+        assert_eq!(blocked.answers[0].data, RData::A(Ipv4Addr::UNSPECIFIED));
+
+        config.rebind.as_mut().unwrap().allow.clear();
+        let filter = ResponseFilter::try_from(&config).unwrap();
+        let mut message = Message::try_from(&response("192.168.1.1")).unwrap();
+        message.answers.push(Record::new(
+            "typo.example",
+            60,
+            RData::A(Ipv4Addr::new(1, 1, 1, 1)),
+        ));
+        let stripped =
+            Message::try_from(&filter.apply(Payload::try_from(&message).unwrap())).unwrap();
+        assert_eq!(stripped.answers.len(), 1);
+        assert_eq!(
+            stripped.answers[0].data,
+            RData::A(Ipv4Addr::new(1, 1, 1, 1))
+        );
+
+        config.rebind.as_mut().unwrap().mode = RebindMode::Refuse;
+        let filter = ResponseFilter::try_from(&config).unwrap();
+        let refused = Message::try_from(&filter.apply(response("127.0.0.1"))).unwrap();
+        assert_eq!(refused.rcode(), rcode::REFUSED);
+        assert!(refused.answers.is_empty());
+    }
+}
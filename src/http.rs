@@ -0,0 +1,278 @@
+//! 管理用的最小 HTTP/1.1 服务：每个连接一个请求，应答后关闭
+use serde::Serialize;
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    time::timeout,
+};
+use tracing::{debug, error, info};
+
+use crate::{admin, resolver::Resolver};
+
+/// 请求头与请求体的长度上限
+const MAX_REQUEST: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// `/top` 未指定 `n` 时返回的条数
+const DEFAULT_TOP: usize = 20;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// `?` 之后的原始查询串
+    pub query: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// 查询参数，不做百分号解码
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    pub fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Self::text(500, format!("{e}\n")),
+        }
+    }
+
+    /// 按错误类型选择状态码
+    pub fn error(e: Error) -> Self {
+        let status = match e.kind() {
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => 400,
+            ErrorKind::NotFound => 404,
+            _ => 500,
+        };
+        Self::text(status, format!("{e}\n"))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// 读取请求头，按 Content-Length 读取请求体
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+    let mut buf = Vec::with_capacity(1024);
+    let header_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buf.len() >= MAX_REQUEST {
+            return Err(invalid("request too large"));
+        }
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let mut body = buf.split_off(header_end);
+    let head = std::str::from_utf8(&buf).map_err(|_| invalid("non-utf8 header"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(invalid("bad request line"));
+    };
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>())
+        .transpose()
+        .map_err(|_| invalid("bad content-length"))?
+        .unwrap_or(0);
+    if content_length > MAX_REQUEST.saturating_sub(header_end) {
+        return Err(invalid("request too large"));
+    }
+
+    if body.len() < content_length {
+        let start = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(content_length);
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Request {
+        method: method.into(),
+        path: path.into(),
+        query: query.into(),
+        body,
+    })
+}
+
+async fn route(request: &Request, resolver: &Resolver) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: resolver.metrics().render().into_bytes(),
+        },
+        ("GET", "/top") => {
+            let Some(top) = resolver.top() else {
+                return Response::text(404, "top statistics disabled\n");
+            };
+            let n = match request.param("n").map(str::parse::<usize>) {
+                None => DEFAULT_TOP,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => return Response::text(400, "bad n\n"),
+            };
+            Response::json(&top.report(n))
+        }
+        ("POST", "/top/reset") => match resolver.top() {
+            Some(top) => {
+                top.reset();
+                Response::text(200, "ok\n")
+            }
+            None => Response::text(404, "top statistics disabled\n"),
+        },
+        ("GET", "/status") => admin::status(resolver).await,
+        ("GET", "/config") => Response::json(&resolver.config()),
+        ("GET", "/stats") => Response::json(&resolver.metrics().stats()),
+        ("GET", "/cache") => admin::cache(resolver).await,
+        ("POST", "/cache/flush") => admin::flush_cache(request, resolver).await,
+        ("POST", "/rules/reload") => admin::reload_rules(resolver).await,
+        ("POST", "/rules/temporary") => admin::add_temporary(request, resolver),
+        ("DELETE", "/rules/temporary") => admin::remove_temporary(request, resolver),
+        ("GET", "/blocking") => Response::json(&resolver.pause().status()),
+        ("POST", "/blocking/pause") => admin::pause_blocking(request, resolver),
+        ("POST", "/blocking/resume") => admin::resume_blocking(request, resolver),
+        ("PUT", path) if path.starts_with(admin::UPSTREAMS) => {
+            admin::set_upstream(request, resolver).await
+        }
+        (
+            _,
+            "/metrics" | "/top" | "/top/reset" | "/status" | "/config" | "/stats" | "/cache"
+            | "/cache/flush" | "/rules/reload" | "/rules/temporary" | "/blocking"
+            | "/blocking/pause" | "/blocking/resume",
+        ) => Response::text(405, "method not allowed\n"),
+        _ => Response::text(404, "not found\n"),
+    }
+}
+
+async fn handle(mut stream: TcpStream, resolver: Arc<Resolver>) -> Result<()> {
+    let response = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => {
+            debug!(method = %request.method, path = %request.path, "http request");
+            route(&request, &resolver).await
+        }
+        Ok(Err(e)) if e.kind() == ErrorKind::InvalidData => Response::text(400, format!("{e}\n")),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(Error::new(ErrorKind::TimedOut, "read request")),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// 命令行使用的客户端，返回状态码与应答体
+pub async fn request(addr: SocketAddr, method: &str, target: &str) -> Result<(u16, String)> {
+    let mut stream = timeout(REQUEST_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "connect"))??;
+    let head = format!(
+        "{method} {target} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    timeout(REQUEST_TIMEOUT, stream.read_to_end(&mut buf))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "read response"))??;
+    let text = String::from_utf8_lossy(&buf);
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad status line"))?;
+    Ok((status, body.into()))
+}
+
+pub async fn serve(listen: SocketAddr, resolver: Arc<Resolver>) -> Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!(%listen, "http");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let resolver = resolver.clone();
+        spawn(async move {
+            if let Err(e) = handle(stream, resolver).await {
+                error!(error = ?e, "http");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_work_content_length_overflow() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let request = format!(
+            "POST /rules HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let e = read_request(&mut server).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    io::{Error, Result},
+    net::IpAddr,
+};
+
+use crate::{
+    config::LocalConfig,
+    message::{rtype, Message, RData, Record},
+};
+
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// 本地静态记录，来自配置与 hosts 文件
+#[derive(Default)]
+pub struct LocalRecords {
+    names: HashMap<String, Vec<Record>>,
+}
+
+impl TryFrom<&LocalConfig> for LocalRecords {
+    type Error = Error;
+
+    fn try_from(config: &LocalConfig) -> Result<Self> {
+        let mut local = Self::default();
+
+        for record in &config.records {
+            let (rtype, data) = record.parse()?;
+            let name = record.name.trim_end_matches('.').to_ascii_lowercase();
+            let ttl = record.ttl.unwrap_or(config.ttl);
+            local.add(Record::with_type(&name, rtype, ttl, data), config.ttl);
+        }
+
+        for file in &config.hosts {
+            let content = read_to_string(file)
+                .map_err(|e| Error::new(e.kind(), format!("hosts {file:?}: {e}")))?;
+            local.add_hosts(&content, config.ttl);
+        }
+
+        Ok(local)
+    }
+}
+
+/// 地址对应的反向解析名，如 `4.3.2.1.in-addr.arpa`
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(72);
+            for b in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", b & 0x0f, b >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// `reverse_name` 的逆操作
+pub fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+    if let Some(v4) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0u8; 4];
+        let labels: Vec<&str> = v4.split('.').collect();
+        if labels.len() != 4 {
+            return None;
+        }
+        for (o, label) in octets.iter_mut().rev().zip(labels) {
+            *o = label.parse().ok()?;
+        }
+        return Some(IpAddr::from(octets));
+    }
+
+    let v6 = name.strip_suffix(".ip6.arpa")?;
+    let nibbles: Vec<&str> = v6.split('.').collect();
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut bits = 0u128;
+    for nibble in nibbles.iter().rev() {
+        if nibble.len() != 1 {
+            return None;
+        }
+        bits = bits << 4 | u8::from_str_radix(nibble, 16).ok()? as u128;
+    }
+    Some(IpAddr::from(bits.to_be_bytes()))
+}
+
+impl LocalRecords {
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// 添加记录，A/AAAA 同时合成对应的 PTR
+    fn add(&mut self, record: Record, ptr_ttl: u32) {
+        let ip: Option<IpAddr> = match record.data {
+            RData::A(ip) => Some(ip.into()),
+            RData::Aaaa(ip) => Some(ip.into()),
+            _ => None,
+        };
+        if let Some(ip) = ip {
+            let ptr = Record::with_type(
+                &reverse_name(ip),
+                rtype::PTR,
+                ptr_ttl,
+                RData::Name(record.name.clone()),
+            );
+            self.insert(ptr);
+        }
+        self.insert(record);
+    }
+
+    fn insert(&mut self, record: Record) {
+        let records = self.names.entry(record.name.clone()).or_default();
+        if !records
+            .iter()
+            .any(|r| r.rtype == record.rtype && r.data == record.data)
+        {
+            records.push(record);
+        }
+    }
+
+    /// 解析 hosts 格式：`<ip> <name> [alias...]`，`#` 之后为注释
+    fn add_hosts(&mut self, content: &str, ttl: u32) {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+                continue;
+            };
+            let data = match ip {
+                IpAddr::V4(ip) => RData::A(ip),
+                IpAddr::V6(ip) => RData::Aaaa(ip),
+            };
+            for name in fields {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                self.add(Record::new(&name, ttl, data.clone()), ttl);
+            }
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains_key(name)
+    }
+
+    /// 名称存在时给出应答（无对应类型时为 NODATA），并在本地跟随 CNAME
+    pub fn answer(&self, query: &Message) -> Option<Message> {
+        let question = query.question()?;
+        let mut records = self.names.get(&question.name.to_ascii_lowercase())?;
+        let mut reply = Message::reply(query);
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let matched: Vec<&Record> = records
+                .iter()
+                .filter(|r| r.rtype == question.qtype || question.qtype == rtype::ANY)
+                .collect();
+            if !matched.is_empty() {
+                reply.answers.extend(matched.into_iter().cloned());
+                break;
+            }
+
+            let Some(cname) = records.iter().find(|r| r.rtype == rtype::CNAME) else {
+                break;
+            };
+            reply.answers.push(cname.clone());
+            match &cname.data {
+                RData::Name(target) => match self.names.get(target) {
+                    Some(next) => records = next,
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn it_work_hosts() {
+        let mut local = LocalRecords::default();
+        local.add_hosts(
+            "# lan\n192.168.1.10 NAS.lan nas # storage\nfd00::10 nas.lan\nbad line\n",
+            60,
+        );
+        local.add(
+            Record::with_type("files.lan", rtype::CNAME, 30, RData::Name("nas.lan".into())),
+            60,
+        );
+
+        let reply = local.answer(&Message::query("nas.lan", rtype::A)).unwrap();
+        assert_eq!(
+            reply.answers[0].data,
+            RData::A(Ipv4Addr::new(192, 168, 1, 10))
+        );
+
+        let reply = local
+            .answer(&Message::query("files.lan", rtype::AAAA))
+            .unwrap();
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(
+            reply.answers[1].data,
+            RData::Aaaa("fd00::10".parse().unwrap())
+        );
+
+        let reply = local
+            .answer(&Message::query("10.1.168.192.in-addr.arpa", rtype::PTR))
+            .unwrap();
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[0].data, RData::Name("nas.lan".into()));
+
+        // 名称存在但无该类型：NODATA
+        let reply = local.answer(&Message::query("nas", rtype::TXT)).unwrap();
+        assert!(reply.answers.is_empty());
+        assert!(local
+            .answer(&Message::query("printer.lan", rtype::A))
+            .is_none());
+    }
+
+    #[test]
+    fn it_work_reverse_name() {
+        let v4 = Ipv4Addr::new(1, 2, 3, 4).into();
+        assert_eq!(reverse_name(v4), "4.3.2.1.in-addr.arpa");
+        assert_eq!(parse_reverse_name("4.3.2.1.in-addr.arpa"), Some(v4));
+
+        let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into();
+        let name = reverse_name(v6);
+        assert!(name.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(name.ends_with(".8.b.d.0.1.0.0.2.ip6.arpa"));
+        assert_eq!(parse_reverse_name(&name), Some(v6));
+
+        assert_eq!(parse_reverse_name("3.2.1.in-addr.arpa"), None);
+        assert_eq!(parse_reverse_name("example.com"), None);
+    }
+}
@@ -0,0 +1,43 @@
+use clap::ValueEnum;
+use std::io::{stdout, Error, ErrorKind, IsTerminal, Result};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// 未指定 `--log` 与 `RUST_LOG` 时的过滤规则
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// 每行一个 JSON 对象，span 字段展开在 `span` 中
+    Json,
+}
+
+/// 过滤规则依次取 `--log`、`RUST_LOG`、默认 `info`，语法同 `EnvFilter`，如 `info,fakedns::dns=debug`
+pub fn init(filter: Option<&str>, format: LogFormat) -> Result<()> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter),
+        None => EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(DEFAULT_FILTER)),
+    }
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("log filter: {e}")))?;
+
+    let layer = match format {
+        LogFormat::Text => fmt::layer().with_ansi(stdout().is_terminal()).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+    .with_filter(filter);
+
+    let registry = tracing_subscriber::registry().with(layer);
+    // console 需要 tokio 的 trace 级事件，不受上面的过滤规则约束
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+
+    registry
+        .try_init()
+        .map_err(|e| Error::new(ErrorKind::AlreadyExists, e.to_string()))
+}
@@ -1,20 +1,60 @@
+mod admin;
+mod chinadns;
+mod cidr;
+mod config;
+mod conn;
 mod dns;
+mod dnstap;
+mod fakeip;
+mod filter;
+mod http;
+mod local;
+mod log;
 mod macros;
+mod message;
+mod metrics;
+mod nftset;
+mod pause;
 mod payload;
+mod poison;
+mod querylog;
+mod resolver;
+mod reverse;
+mod router;
+mod state;
+mod supervisor;
+mod topk;
 mod trie;
+mod upstream;
+mod zone;
 
-use clap::Parser;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use clap::{Parser, Subcommand};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::{
     net::UdpSocket,
-    spawn,
-    sync::{mpsc, oneshot},
+    select,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    task::{JoinError, JoinSet},
+    time::{sleep, timeout},
 };
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    dns::{Dns, DnsCommand},
+    config::{ChinaDnsConfig, Config, HttpConfig},
+    log::LogFormat,
+    message::Message,
     payload::Payload,
-    trie::DomainTrie,
+    resolver::Resolver,
+    router::{Route, Router},
+    supervisor::supervise,
 };
 
 #[derive(Parser, Debug)]
@@ -31,198 +71,351 @@ pub struct Args {
     /// ExcludeDomain
     #[arg(short, long, default_value = "deploy/conf.d/domain_exclude.conf")]
     exclude_domain: PathBuf,
-}
 
-const RESPONSE_START: &[u8] = &[0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01];
-const RESPONSE_END: &[u8] = &[
-    0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0xf4, 0x00, 0x04, // prefix
-    0x00, 0x00, 0x00, 0x00, // ip: 0.0.0.0
-    0x00, 0x00, 0x29, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // hotfix
-];
-const MAX_BUFFER: usize = 5;
+    /// Config, replaces the domain lists above with its routing table
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Answer Domain with fake IPs from 198.18.0.0/15 instead of forwarding (without --config)
+    #[arg(long)]
+    fake_ip: bool,
 
-fn fake_response(buf: &[u8], end_offset: usize) -> Vec<u8> {
-    // "8fd6 0120 0001000000000001 0378723105766c70657203746f700000010001 0000291000000000000000"
-    // "e116 0120 0001000000000001 02787205766c70657203746f700000010001 0000291000000000000000"
+    /// Send unlisted domains to both upstreams, keep the direct answer only if its IPs are in this CIDR list (without --config)
+    #[arg(long)]
+    china_ip: Option<PathBuf>,
 
-    // "e116 8180 0001000100000001 02787205766c70657203746f7000 00010001 c00c0001000100000001 0004 cebeee93 0000290580000000000000"
-    // "9e31 8180 0001000100000001 02787205766c70657203746f7000 00010001 c00c00010001000001f4 0004 7f000001 0000290580000000000000"
+    /// Log filter, e.g. `debug` or `info,fakedns::dns=trace` (defaults to RUST_LOG, then `info`)
+    #[arg(long)]
+    log: Option<String>,
 
-    // "d939 0120 0001000000000001 0377777706676f6f676c6503636f6d00 00010001 0000291000000000000000"
-    // "d939 8180 0001000100000001 0377777706676f6f676c6503636f6d00 000100   c00c00010001000001f400047f0000010000290580000000000000"
-    // "61f5 8180 0001000100000001 0377777706676f6f676c6503636f6d00 00010001 c00c00010001000001f400047f0000010000290580000000000000"
+    /// Log output format
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
 
-    let mut r: Vec<u8> = Vec::new();
-    r.extend_from_slice(&buf[..2]);
-    r.extend_from_slice(RESPONSE_START);
-    r.extend_from_slice(&buf[12..end_offset + 5]);
-    r.extend_from_slice(RESPONSE_END);
+    /// Serve the admin API on this address, overriding `[http]` in the config
+    #[arg(long)]
+    http: Option<SocketAddr>,
 
-    r
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn create_tx(sock_local: Arc<UdpSocket>, addr: &str) -> mpsc::Sender<(Payload, SocketAddr)> {
-    let dns = Dns::new(addr).await;
-    let (tx_dns, rx_dns) = mpsc::channel::<DnsCommand>(MAX_BUFFER);
-    let dns_cloned = dns.clone();
-    spawn(async move { dns_cloned.work_cmd(rx_dns).await });
-    spawn(async move { dns.work_response().await });
-    let (tx_req, mut rx_req) = mpsc::channel::<(Payload, SocketAddr)>(MAX_BUFFER);
-    spawn(async move {
-        while let Some((mut payload, addr)) = rx_req.recv().await {
-            #[cfg(debug_assertions)]
-            println!("[+] {addr:?} send raw request");
-
-            let (resp, rx) = oneshot::channel::<Payload>();
-            let id = payload.id();
-            {
-                let payload = payload.clone();
-                tx_dns
-                    .send(DnsCommand::Query { payload, resp })
-                    .await
-                    .expect("[E] raw request dns cmd query");
-            }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print which rule a domain matches and how it would be routed
+    Explain { domain: String },
+    /// Pause block rules on a running instance, re-enabled automatically afterwards
+    Pause {
+        /// How long, e.g. `90`, `30s`, `10m`, `1h`
+        #[arg(default_value = "5m", value_parser = parse_duration)]
+        duration: Duration,
+        /// Only for this client, otherwise for everyone
+        #[arg(long)]
+        client: Option<IpAddr>,
+        /// Admin API address (defaults to `--http`, then `[http]` in the config)
+        #[arg(long)]
+        admin: Option<SocketAddr>,
+    },
+    /// Re-enable block rules paused with `pause`
+    Resume {
+        #[arg(long)]
+        client: Option<IpAddr>,
+        #[arg(long)]
+        admin: Option<SocketAddr>,
+    },
+}
 
-            // 3ms
-            let timeout = 300;
-            let payload = handle!(handle!(cancel!(rx, timeout), e => {
-                println!("[E] raw request dns rx {e:?} {addr:?}");
-                tx_dns
-                .send(DnsCommand::TimedOut { id })
-                .await
-                .expect("[E] raw request dns cmd timedout");
-                payload.servfail();
-                Ok(payload)
-            }), e => {
-                println!("[E] raw request dns rx {e:?} {addr:?}");
-                continue;
-            });
+/// 不带单位时为秒
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {s:?}"))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => {
+            return Err(format!(
+                "invalid duration unit {unit:?}, expected s, m or h"
+            ))
+        }
+    };
+    let secs = number
+        .checked_mul(scale)
+        .ok_or_else(|| format!("invalid duration {s:?}"))?;
+    match secs {
+        0 => Err("duration must be positive".into()),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
 
-            #[cfg(debug_assertions)]
-            println!("[+] {addr:?} raw request id {} {}", id, payload.id());
+/// 调用运行中实例的管理接口并打印应答
+async fn admin_request(admin: Option<SocketAddr>, target: &str) {
+    let Some(admin) = admin else {
+        eprintln!("no admin address: pass --admin, --http or configure [http]");
+        std::process::exit(2);
+    };
+    match http::request(admin, "POST", target).await {
+        Ok((200, body)) => print!("{body}"),
+        Ok((status, body)) => {
+            eprint!("{status} {body}");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{admin}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
 
-            let _len = sock_local
-                .send_to(payload.as_ref(), &addr)
-                .await
-                .expect("[E] raw response send_to");
+const FAKE_IP_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// 收到退出信号后等待进行中查询的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
-            #[cfg(debug_assertions)]
-            println!("[+] {addr:?} send raw response({_len:?})");
+/// 等待 SIGTERM 或 SIGINT，返回信号名
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("[E] signal SIGTERM");
+    select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = ctrl_c() => "SIGINT",
+    }
+}
+
+/// 应答一个查询，出错只影响这个查询
+async fn serve_query(
+    resolver: Arc<Resolver>,
+    sock: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    payload: Payload,
+    addr: SocketAddr,
+) {
+    let query_time = SystemTime::now();
+    let payload = resolver.resolve(payload, addr).await;
+
+    match sock.send_to(payload.as_ref(), &addr).await {
+        Ok(len) => trace!(client = %addr, len, "send response"),
+        Err(e) => {
+            warn!(client = %addr, error = ?e, "send response");
+            resolver.metrics().errors.inc("send");
+            return;
         }
-    });
-    tx_req
+    }
+    if let Some(dnstap) = resolver.dnstap() {
+        dnstap.client_response(addr, local_addr, query_time, &payload);
+    }
+}
+
+/// panic 的查询只记录，不影响其他查询
+fn query_joined(resolver: &Resolver, joined: Result<(), JoinError>) {
+    match joined {
+        Err(e) if e.is_panic() => {
+            error!(error = ?e, "query panicked");
+            resolver.metrics().errors.inc("panic");
+        }
+        _ => {}
+    }
+}
+
+fn explain(router: &Router, chinadns: Option<&ChinaDnsConfig>, domain: &str) {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let reversed: Vec<&str> = domain.split('.').rev().collect();
+
+    println!("domain: {domain}");
+    for (i, (rule, matched)) in router.explain(&reversed).enumerate() {
+        match matched {
+            Some(m) => println!("  [{}] {:<12} {m}", i + 1, rule.name),
+            None => println!("  [{}] {:<12} -", i + 1, rule.name),
+        }
+    }
+    match router.route(&reversed) {
+        Route::Rule(rule, m) => {
+            println!("route: rule {:?} via {m} -> {:?}", rule.name, rule.action)
+        }
+        Route::Default(group) => match chinadns {
+            Some(c) => println!(
+                "route: default -> chinadns {:?}, {:?} if not in {:?}",
+                c.domestic, c.foreign, c.cidr
+            ),
+            None => println!("route: default -> forward {group:?}"),
+        },
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    // init console subscriber
-    #[cfg(feature = "console")]
-    console_subscriber::init();
-
     let Args {
         domain,
         block_domain,
         exclude_domain,
+        config,
+        fake_ip,
+        china_ip,
+        log,
+        log_format,
+        http,
+        command,
     } = Args::parse();
-    println!("[+] domain: {domain:?}");
-    println!("[+] block_domain: {block_domain:?}");
-    println!("[+] exclude_domain: {exclude_domain:?}");
 
-    let trie = Arc::new(DomainTrie::try_from(domain.as_path()).expect("[E] domain trie"));
-    let block_trie =
-        Arc::new(DomainTrie::try_from(block_domain.as_path()).expect("[E] block domain trie"));
-    let exclude_trie =
-        Arc::new(DomainTrie::try_from(exclude_domain.as_path()).expect("[E] exclude domain trie"));
+    log::init(log.as_deref(), log_format).expect("[E] log");
 
-    let sock_local = Arc::new(UdpSocket::bind("0.0.0.0:53").await.expect("[E] bind 0:53"));
-    println!("[+] bind: 53");
-
-    let alidns_req_tx = create_tx(sock_local.clone(), "223.5.5.5:53").await;
-    let ggdns_req_tx = create_tx(sock_local.clone(), "8.8.8.8:53").await;
-
-    let sock_local_c = sock_local.clone();
-    let (tx, mut rx) = mpsc::channel::<(Payload, SocketAddr)>(MAX_BUFFER);
-    spawn(async move {
-        while let Some((payload, addr)) = rx.recv().await {
-            let ggdns_req_tx = ggdns_req_tx.clone();
-            let alidns_req_tx = alidns_req_tx.clone();
-            let sock_local_c = sock_local_c.clone();
-            let exclude_trie = exclude_trie.clone();
-            let block_trie = block_trie.clone();
-            let trie = trie.clone();
-
-            spawn(async move {
-                let (domain, end_offset) = payload.domain();
-
-                #[cfg(debug_assertions)]
-                {
-                    let domain: Vec<_> = domain
-                        .iter()
-                        .map(|v| String::from_utf8(v.to_ascii_lowercase()).unwrap())
-                        .collect();
-                    println!(
-                        "[+] {addr:?} offset {end_offset:?} domain {:?}",
-                        domain.join(".").as_str()
-                    );
-                }
+    let mut config = match config {
+        Some(config) => Config::try_from(config.as_path()).expect("[E] config"),
+        None => {
+            let mut config = match fake_ip {
+                true => Config::legacy_fake_ip(&domain, &block_domain, &exclude_domain),
+                false => Config::legacy(&domain, &block_domain, &exclude_domain),
+            };
+            config.chinadns = china_ip.as_deref().map(ChinaDnsConfig::legacy);
+            config
+        }
+    };
 
-                // exclude
-                let is = exclude_trie.domain_prefix_match(&domain);
-                #[cfg(debug_assertions)]
-                println!("[+] {addr:?} exclude {is:?}");
-                if is {
-                    alidns_req_tx
-                        .send((payload, addr))
-                        .await
-                        .expect("[E] alidns_req_tx send");
-                    return;
-                }
+    if let Some(listen) = http {
+        config.http = Some(HttpConfig { listen });
+    }
+    let admin_addr = config.http.as_ref().map(|http| http.listen);
 
-                // block
-                let is = block_trie.domain_prefix_match(&domain);
-                #[cfg(debug_assertions)]
-                println!("[+] {addr:?} block {is:?}");
-                if is {
-                    let buf = fake_response(payload.as_ref(), end_offset);
-                    let _len = sock_local_c
-                        .send_to(&buf, &addr)
-                        .await
-                        .expect("[E] sock_local send_to");
-
-                    #[cfg(debug_assertions)]
-                    println!("[+] {addr:?} send fake response({_len:?})");
-                    return;
-                }
+    match command {
+        Some(Command::Explain { domain }) => {
+            let router = Router::try_from(&config).expect("[E] router");
+            explain(&router, config.chinadns.as_ref(), &domain);
+            return;
+        }
+        Some(Command::Pause {
+            duration,
+            client,
+            admin,
+        }) => {
+            let mut target = format!("/blocking/pause?duration={}", duration.as_secs());
+            if let Some(client) = client {
+                target += &format!("&client={client}");
+            }
+            admin_request(admin.or(admin_addr), &target).await;
+            return;
+        }
+        Some(Command::Resume { client, admin }) => {
+            let target = match client {
+                Some(client) => format!("/blocking/resume?client={client}"),
+                None => "/blocking/resume".into(),
+            };
+            admin_request(admin.or(admin_addr), &target).await;
+            return;
+        }
+        None => {}
+    }
+
+    for rule in &config.rules {
+        info!(rule = %rule.name, file = ?rule.file, action = ?rule.action, "rule loaded");
+    }
 
-                let is = trie.domain_prefix_match(&domain);
-                #[cfg(debug_assertions)]
-                println!("[+] {addr:?} proxy {is:?}");
-                if is {
-                    ggdns_req_tx
-                        .send((payload, addr))
-                        .await
-                        .expect("[E] ggdns_req_tx send");
-                } else {
-                    alidns_req_tx
-                        .send((payload, addr))
-                        .await
-                        .expect("[E] alidns_req_tx send");
+    let resolver = Arc::new(Resolver::new(&config).await.expect("[E] resolver"));
+
+    let restarts = resolver.metrics().restarts.clone();
+    if let Some(fake_ip) = resolver.fake_ip().cloned() {
+        supervise("fake-ip save", restarts.clone(), move || {
+            let fake_ip = fake_ip.clone();
+            async move {
+                loop {
+                    sleep(FAKE_IP_SAVE_INTERVAL).await;
+                    if let Err(e) = fake_ip.save() {
+                        error!(error = ?e, "fake ip save");
+                    }
                 }
-            });
-        }
-    });
+            }
+        });
+    }
+
+    if let Some(reverse) = config.reverse.clone() {
+        let resolver = resolver.clone();
+        supervise("reverse lookup", restarts.clone(), move || {
+            let socket = reverse.socket.clone();
+            let resolver = resolver.clone();
+            async move { reverse::serve(&socket, resolver).await }
+        });
+    }
+
+    if let Some(http) = config.http.clone() {
+        let resolver = resolver.clone();
+        supervise("http", restarts.clone(), move || {
+            http::serve(http.listen, resolver.clone())
+        });
+    }
 
+    let sock_local = Arc::new(UdpSocket::bind("0.0.0.0:53").await.expect("[E] bind 0:53"));
+    let local_addr = sock_local.local_addr().expect("[E] sock_local local_addr");
+    info!("bind: 53");
+
+    let mut queries = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     let mut buf = [0; 1024];
     loop {
-        let (len, addr) = sock_local
-            .recv_from(&mut buf)
-            .await
-            .expect("[E] sock_local recv_from");
-        #[cfg(debug_assertions)]
-        println!("[+] {addr:?} recv request({len:?})");
-
-        tx.send((Payload::from(&buf[..len]), addr))
-            .await
-            .expect("[E] tx send");
+        let (len, addr) = select! {
+            received = sock_local.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!(error = ?e, "sock_local recv_from");
+                    resolver.metrics().errors.inc("recv");
+                    continue;
+                }
+            },
+            Some(joined) = queries.join_next(), if !queries.is_empty() => {
+                query_joined(&resolver, joined);
+                continue;
+            }
+            signal = &mut shutdown => {
+                info!(signal, "shutting down");
+                break;
+            }
+        };
+        trace!(client = %addr, len, "recv request");
+        let payload = Payload::from(&buf[..len]);
+        // 解析不了或没有问题的请求直接丢弃，不再往下传
+        match Message::try_from(&payload) {
+            Ok(query) if query.question().is_some() => {}
+            parsed => {
+                debug!(client = %addr, len, error = ?parsed.err(), "malformed query");
+                resolver.metrics().errors.inc("malformed");
+                continue;
+            }
+        }
+        if let Some(dnstap) = resolver.dnstap() {
+            dnstap.client_query(addr, local_addr, &payload);
+        }
+
+        queries.spawn(serve_query(
+            resolver.clone(),
+            sock_local.clone(),
+            local_addr,
+            payload,
+            addr,
+        ));
+    }
+
+    // 不再接收新查询，等待进行中的查询应答
+    let drained = timeout(SHUTDOWN_TIMEOUT, async {
+        while let Some(joined) = queries.join_next().await {
+            query_joined(&resolver, joined);
+        }
+    })
+    .await;
+    let mut failed = false;
+    if drained.is_err() {
+        warn!(
+            abandoned = queries.len(),
+            "queries still pending at shutdown"
+        );
+        queries.abort_all();
+        failed = true;
+    }
+
+    if let Err(e) = resolver.shutdown().await {
+        error!(error = ?e, "shutdown");
+        failed = true;
+    }
+    info!("stopped");
+    if failed {
+        std::process::exit(1);
     }
 }
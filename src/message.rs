@@ -0,0 +1,821 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::payload::Payload;
+
+pub mod rtype {
+    pub const A: u16 = 1;
+    pub const NS: u16 = 2;
+    pub const CNAME: u16 = 5;
+    pub const SOA: u16 = 6;
+    pub const PTR: u16 = 12;
+    pub const MX: u16 = 15;
+    pub const TXT: u16 = 16;
+    pub const AAAA: u16 = 28;
+    pub const SRV: u16 = 33;
+    pub const OPT: u16 = 41;
+    pub const SVCB: u16 = 64;
+    pub const HTTPS: u16 = 65;
+    pub const ANY: u16 = 255;
+
+    const NAMES: &[(&str, u16)] = &[
+        ("A", A),
+        ("NS", NS),
+        ("CNAME", CNAME),
+        ("SOA", SOA),
+        ("PTR", PTR),
+        ("MX", MX),
+        ("TXT", TXT),
+        ("AAAA", AAAA),
+        ("SRV", SRV),
+        ("OPT", OPT),
+        ("SVCB", SVCB),
+        ("HTTPS", HTTPS),
+        ("ANY", ANY),
+    ];
+
+    pub fn from_name(name: &str) -> Option<u16> {
+        NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, t)| *t)
+            .or_else(|| name.strip_prefix("TYPE").and_then(|t| t.parse().ok()))
+    }
+
+    pub fn name(rtype: u16) -> String {
+        match NAMES.iter().find(|(_, t)| *t == rtype) {
+            Some((n, _)) => n.to_string(),
+            None => format!("TYPE{rtype}"),
+        }
+    }
+}
+
+pub mod rcode {
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+    pub const REFUSED: u8 = 5;
+
+    const NAMES: &[&str] = &[
+        "NOERROR", "FORMERR", "SERVFAIL", "NXDOMAIN", "NOTIMP", "REFUSED",
+    ];
+
+    pub fn name(rcode: u8) -> String {
+        match NAMES.get(rcode as usize) {
+            Some(n) => n.to_string(),
+            None => format!("RCODE{rcode}"),
+        }
+    }
+}
+
+pub const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    /// CNAME / NS / PTR
+    Name(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Soa {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Txt(Vec<Vec<u8>>),
+    Raw(Vec<u8>),
+}
+
+impl RData {
+    /// 解析文本形式的记录值，名称需为绝对名称（不带结尾的点）
+    pub fn from_text(rtype: u16, value: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid {} value {value:?}", rtype::name(rtype)),
+            )
+        };
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let name = |s: &str| s.trim_end_matches('.').to_ascii_lowercase();
+        let num = |i: usize| -> Result<u32> {
+            fields
+                .get(i)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(invalid)
+        };
+
+        let data = match rtype {
+            rtype::A => RData::A(value.trim().parse().map_err(|_| invalid())?),
+            rtype::AAAA => RData::Aaaa(value.trim().parse().map_err(|_| invalid())?),
+            rtype::CNAME | rtype::NS | rtype::PTR if fields.len() == 1 => {
+                RData::Name(name(fields[0]))
+            }
+            rtype::MX if fields.len() == 2 => RData::Mx {
+                preference: num(0)? as u16,
+                exchange: name(fields[1]),
+            },
+            rtype::SRV if fields.len() == 4 => RData::Srv {
+                priority: num(0)? as u16,
+                weight: num(1)? as u16,
+                port: num(2)? as u16,
+                target: name(fields[3]),
+            },
+            rtype::SOA if fields.len() == 7 => RData::Soa {
+                mname: name(fields[0]),
+                rname: name(fields[1]),
+                serial: num(2)?,
+                refresh: num(3)?,
+                retry: num(4)?,
+                expire: num(5)?,
+                minimum: num(6)?,
+            },
+            rtype::TXT => RData::Txt(split_txt(value).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        Ok(data)
+    }
+}
+
+/// 拆分 TXT 记录值：带引号的字符串各成一段，否则整体作为一段
+fn split_txt(value: &str) -> Option<Vec<Vec<u8>>> {
+    let value = value.trim();
+    if !value.starts_with('"') {
+        return Some(vec![value.as_bytes().to_vec()]);
+    }
+
+    let mut strings = Vec::new();
+    let mut chars = value.chars();
+    loop {
+        match chars.find(|c| !c.is_whitespace()) {
+            None => break,
+            Some('"') => {}
+            Some(_) => return None,
+        }
+        let mut s = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => s.push(chars.next()?),
+                c => s.push(c),
+            }
+        }
+        strings.push(s.into_bytes());
+    }
+    Some(strings)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+impl Record {
+    pub fn new(name: &str, ttl: u32, data: RData) -> Self {
+        let rtype = match &data {
+            RData::A(_) => rtype::A,
+            RData::Aaaa(_) => rtype::AAAA,
+            RData::Mx { .. } => rtype::MX,
+            RData::Srv { .. } => rtype::SRV,
+            RData::Soa { .. } => rtype::SOA,
+            RData::Txt(_) => rtype::TXT,
+            RData::Name(_) | RData::Raw(_) => 0,
+        };
+        Self::with_type(name, rtype, ttl, data)
+    }
+
+    pub fn with_type(name: &str, rtype: u16, ttl: u32, data: RData) -> Self {
+        Self {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl,
+            data,
+        }
+    }
+
+    /// A/AAAA 记录的地址
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.data {
+            RData::A(ip) => Some(ip.into()),
+            RData::Aaaa(ip) => Some(ip.into()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl TryFrom<&[u8]> for Message {
+    type Error = Error;
+
+    fn try_from(buf: &[u8]) -> Result<Self> {
+        let mut r = Reader { buf, pos: 0 };
+        let id = r.u16()?;
+        let flags = r.u16()?;
+        let counts = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+
+        let mut questions = Vec::with_capacity(counts[0] as usize);
+        for _ in 0..counts[0] {
+            questions.push(Question {
+                name: r.name()?,
+                qtype: r.u16()?,
+                qclass: r.u16()?,
+            });
+        }
+
+        let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+        for (section, &count) in sections.iter_mut().zip(&counts[1..]) {
+            for _ in 0..count {
+                section.push(r.record()?);
+            }
+        }
+        let [answers, authorities, additionals] = sections;
+
+        Ok(Self {
+            id,
+            flags,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+}
+
+impl TryFrom<&Payload> for Message {
+    type Error = Error;
+
+    fn try_from(payload: &Payload) -> Result<Self> {
+        Self::try_from(payload.as_ref())
+    }
+}
+
+impl TryFrom<&Message> for Payload {
+    type Error = Error;
+
+    fn try_from(message: &Message) -> Result<Self> {
+        message.encode().map(Payload)
+    }
+}
+
+impl Message {
+    /// 以请求为模板构造应答：复制 id、opcode、RD 与问题，并回显 EDNS OPT
+    pub fn reply(query: &Message) -> Self {
+        let additionals = query
+            .edns()
+            .map(|opt| Record {
+                name: String::new(),
+                rtype: rtype::OPT,
+                class: opt.class.max(512),
+                ttl: 0,
+                data: RData::Raw(Vec::new()),
+            })
+            .into_iter()
+            .collect();
+
+        Self {
+            id: query.id,
+            flags: FLAG_QR | FLAG_RA | (query.flags & (OPCODE_MASK | FLAG_RD)),
+            questions: query.questions.clone(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals,
+        }
+    }
+
+    /// 测试用：只含一个问题的查询
+    #[cfg(test)]
+    pub fn query(name: &str, qtype: u16) -> Self {
+        Self {
+            id: 7,
+            flags: FLAG_RD,
+            questions: vec![Question {
+                name: name.into(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    pub fn question(&self) -> Option<&Question> {
+        self.questions.first()
+    }
+
+    pub fn edns(&self) -> Option<&Record> {
+        self.additionals.iter().find(|r| r.rtype == rtype::OPT)
+    }
+
+    /// 应答中的 A/AAAA 地址及其 TTL
+    pub fn answer_ips(&self) -> impl Iterator<Item = (IpAddr, u32)> + '_ {
+        self.answers
+            .iter()
+            .filter_map(|r| r.ip().map(|ip| (ip, r.ttl)))
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+
+    pub fn set_rcode(&mut self, rcode: u8) {
+        self.flags = (self.flags & !0x000f) | rcode as u16;
+    }
+
+    pub fn set_authoritative(&mut self, aa: bool) {
+        if aa {
+            self.flags |= FLAG_AA;
+        } else {
+            self.flags &= !FLAG_AA;
+        }
+    }
+
+    /// 名称中有空标签或超长标签时返回 `InvalidInput`
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut w = Writer {
+            buf: Vec::with_capacity(512),
+            names: HashMap::new(),
+        };
+        w.u16(self.id);
+        w.u16(self.flags);
+        w.u16(self.questions.len() as u16);
+        w.u16(self.answers.len() as u16);
+        w.u16(self.authorities.len() as u16);
+        w.u16(self.additionals.len() as u16);
+
+        for q in &self.questions {
+            w.name(&q.name)?;
+            w.u16(q.qtype);
+            w.u16(q.qclass);
+        }
+        for r in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            w.record(r)?;
+        }
+        Ok(w.buf)
+    }
+}
+
+/// 各资源记录 TTL 字段的偏移，不含 OPT（其 TTL 字段为扩展 RCODE 与标志）
+pub fn ttl_offsets(buf: &[u8]) -> Result<Vec<usize>> {
+    let mut r = Reader { buf, pos: 4 };
+    let counts = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+    for _ in 0..counts[0] {
+        r.name()?;
+        r.bytes(4)?;
+    }
+
+    let records = counts[1..].iter().map(|&c| c as usize).sum();
+    let mut offsets = Vec::with_capacity(records);
+    for _ in 0..records {
+        r.name()?;
+        let rtype = r.u16()?;
+        r.bytes(2)?;
+        if rtype != rtype::OPT {
+            offsets.push(r.pos);
+        }
+        r.bytes(4)?;
+        let len = r.u16()? as usize;
+        r.bytes(len)?;
+    }
+    Ok(offsets)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn eof() -> Error {
+        Error::new(ErrorKind::UnexpectedEof, "truncated dns message")
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self.pos + len;
+        let bytes = self.buf.get(self.pos..end).ok_or_else(Self::eof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// 读取（可能被压缩的）域名，保留原始大小写，特殊字节按 `\.`、`\DDD` 转义
+    fn name(&mut self) -> Result<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        let mut jumps = 0;
+
+        loop {
+            let len = *self.buf.get(pos).ok_or_else(Self::eof)? as usize;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self.buf.get(pos + 1..pos + 1 + len).ok_or_else(Self::eof)?;
+                    labels.push(escape_label(label));
+                    pos += len + 1;
+                }
+                0xc0 => {
+                    let low = *self.buf.get(pos + 1).ok_or_else(Self::eof)? as usize;
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    jumps += 1;
+                    if jumps > 64 {
+                        return Err(Error::new(ErrorKind::InvalidData, "name pointer loop"));
+                    }
+                    pos = (len & 0x3f) << 8 | low;
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid label type")),
+            }
+        }
+
+        if !jumped {
+            self.pos = pos;
+        }
+        Ok(labels.join("."))
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        if end > self.buf.len() {
+            return Err(Self::eof());
+        }
+
+        let data = match rtype {
+            rtype::A if len == 4 => {
+                let b = self.bytes(4)?;
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            rtype::AAAA if len == 16 => {
+                let b: [u8; 16] = self.bytes(16)?.try_into().unwrap();
+                RData::Aaaa(Ipv6Addr::from(b))
+            }
+            rtype::CNAME | rtype::NS | rtype::PTR => RData::Name(self.name()?),
+            rtype::MX => RData::Mx {
+                preference: self.u16()?,
+                exchange: self.name()?,
+            },
+            rtype::SRV => RData::Srv {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            rtype::SOA => RData::Soa {
+                mname: self.name()?,
+                rname: self.name()?,
+                serial: self.u32()?,
+                refresh: self.u32()?,
+                retry: self.u32()?,
+                expire: self.u32()?,
+                minimum: self.u32()?,
+            },
+            rtype::TXT => {
+                let mut strings = Vec::new();
+                while self.pos < end {
+                    let len = self.u8()? as usize;
+                    strings.push(self.bytes(len)?.to_vec());
+                }
+                RData::Txt(strings)
+            }
+            _ => RData::Raw(self.bytes(len)?.to_vec()),
+        };
+
+        if self.pos != end {
+            return Err(Error::new(ErrorKind::InvalidData, "rdata length mismatch"));
+        }
+
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            data,
+        })
+    }
+}
+
+/// 标签转为文本，可还原为原始字节
+fn escape_label(label: &[u8]) -> String {
+    let mut s = String::with_capacity(label.len());
+    for &b in label {
+        match b {
+            b'.' | b'\\' => {
+                s.push('\\');
+                s.push(b as char);
+            }
+            0x21..=0x7e => s.push(b as char),
+            _ => s.push_str(&format!("\\{b:03}")),
+        }
+    }
+    s
+}
+
+/// 文本域名拆为标签，允许一个结尾的点；空标签、超长标签或名称时返回 `InvalidInput`
+fn name_labels(name: &str) -> Result<Vec<Vec<u8>>> {
+    let invalid = |reason: &str| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{reason} in name {name:?}"),
+        )
+    };
+    let mut labels = Vec::new();
+    if name.is_empty() || name == "." {
+        return Ok(labels);
+    }
+
+    let mut label = Vec::new();
+    let mut trailing_dot = false;
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        trailing_dot = false;
+        match b {
+            b'.' => {
+                labels.push(std::mem::take(&mut label));
+                trailing_dot = true;
+            }
+            b'\\' => match bytes.next() {
+                Some(d) if d.is_ascii_digit() => {
+                    let digits = [Some(d), bytes.next(), bytes.next()];
+                    let value = digits.iter().try_fold(0u16, |v, d| match d {
+                        Some(d) if d.is_ascii_digit() => Some(v * 10 + (d - b'0') as u16),
+                        _ => None,
+                    });
+                    match value.and_then(|v| u8::try_from(v).ok()) {
+                        Some(v) => label.push(v),
+                        None => return Err(invalid("bad escape")),
+                    }
+                }
+                Some(c) => label.push(c),
+                None => return Err(invalid("bad escape")),
+            },
+            b => label.push(b),
+        }
+    }
+    if !trailing_dot {
+        labels.push(label);
+    }
+
+    if labels.iter().any(|l| l.is_empty()) {
+        return Err(invalid("empty label"));
+    }
+    if labels.iter().any(|l| l.len() > 63) {
+        return Err(invalid("label over 63 bytes"));
+    }
+    if labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1 > 255 {
+        return Err(invalid("over 255 bytes"));
+    }
+    Ok(labels)
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    /// 已写入的名称后缀（按原始字节）及其偏移
+    names: HashMap<Vec<Vec<u8>>, u16>,
+}
+
+impl Writer {
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    /// 写入域名，已出现过的后缀用指针压缩；只压缩大小写完全相同的后缀，保留各处原样
+    fn name(&mut self, name: &str) -> Result<()> {
+        let labels = name_labels(name)?;
+        for i in 0..labels.len() {
+            let suffix = &labels[i..];
+            if let Some(&offset) = self.names.get(suffix) {
+                self.u16(0xc000 | offset);
+                return Ok(());
+            }
+            if self.buf.len() < 0x3fff {
+                self.names.insert(suffix.to_vec(), self.buf.len() as u16);
+            }
+
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(&labels[i]);
+        }
+        self.buf.push(0);
+        Ok(())
+    }
+
+    fn record(&mut self, r: &Record) -> Result<()> {
+        self.name(&r.name)?;
+        self.u16(r.rtype);
+        self.u16(r.class);
+        self.u32(r.ttl);
+
+        let len_at = self.buf.len();
+        self.u16(0);
+        match &r.data {
+            RData::A(ip) => self.buf.extend_from_slice(&ip.octets()),
+            RData::Aaaa(ip) => self.buf.extend_from_slice(&ip.octets()),
+            RData::Name(name) => self.name(name)?,
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                self.u16(*preference);
+                self.name(exchange)?;
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                self.u16(*priority);
+                self.u16(*weight);
+                self.u16(*port);
+                // RFC 2782: SRV target 不允许压缩
+                let names = std::mem::take(&mut self.names);
+                let written = self.name(target);
+                self.names = names;
+                written?;
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                self.name(mname)?;
+                self.name(rname)?;
+                for v in [serial, refresh, retry, expire, minimum] {
+                    self.u32(*v);
+                }
+            }
+            RData::Txt(strings) => {
+                for s in strings {
+                    for chunk in s.chunks(255) {
+                        self.buf.push(chunk.len() as u8);
+                        self.buf.extend_from_slice(chunk);
+                    }
+                }
+            }
+            RData::Raw(raw) => self.buf.extend_from_slice(raw),
+        }
+
+        let len = (self.buf.len() - len_at - 2) as u16;
+        self.buf[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] = &[
+        0x8f, 0xd6, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x78, 0x72,
+        0x31, 0x05, 0x76, 0x6c, 0x70, 0x65, 0x72, 0x03, 0x74, 0x6f, 0x70, 0x00, 0x00, 0x01, 0x00,
+        0x01, 0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn it_work_parse_query() {
+        let m = Message::try_from(QUERY).unwrap();
+        assert_eq!(m.id, 0x8fd6);
+        assert_eq!(m.question().unwrap().name, "xr1.vlper.top");
+        assert_eq!(m.question().unwrap().qtype, rtype::A);
+        assert_eq!(m.edns().unwrap().class, 0x1000);
+        assert_eq!(m.encode().unwrap(), QUERY);
+    }
+
+    #[test]
+    fn it_work_reply_roundtrip() {
+        let query = Message::try_from(QUERY).unwrap();
+        let mut reply = Message::reply(&query);
+        reply.answers.push(Record::new(
+            "xr1.vlper.top",
+            60,
+            RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+        ));
+        reply.answers.push(Record::with_type(
+            "xr1.vlper.top",
+            rtype::CNAME,
+            60,
+            RData::Name("cdn.vlper.top".into()),
+        ));
+        reply.authorities.push(Record::new(
+            "vlper.top",
+            60,
+            RData::from_text(rtype::SOA, "ns1.vlper.top. admin.vlper.top. 1 2 3 4 5").unwrap(),
+        ));
+
+        let buf = reply.encode().unwrap();
+        // 问题名被压缩引用
+        assert_eq!(buf.len(), 122);
+        let parsed = Message::try_from(&buf[..]).unwrap();
+        assert_eq!(parsed, reply);
+        assert_eq!(parsed.flags & FLAG_QR, FLAG_QR);
+    }
+
+    #[test]
+    fn it_work_name_case() {
+        // 0x20 随机大小写原样写回
+        let mut query = Message::try_from(QUERY).unwrap();
+        query.questions[0].name = "xR1.VlPeR.tOp".into();
+        let parsed = Message::try_from(&query.encode().unwrap()[..]).unwrap();
+        assert_eq!(parsed.question().unwrap().name, "xR1.VlPeR.tOp");
+
+        for name in ["a..b", ".a", &"x".repeat(64)] {
+            query.questions[0].name = name.into();
+            let e = query.encode().unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{name}");
+        }
+    }
+
+    #[test]
+    fn it_work_rdata_from_text() {
+        assert_eq!(
+            RData::from_text(rtype::TXT, r#""v=spf1" "a \"b\"""#).unwrap(),
+            RData::Txt(vec![b"v=spf1".to_vec(), b"a \"b\"".to_vec()])
+        );
+        assert_eq!(
+            RData::from_text(rtype::MX, "10 Mail.Example.com.").unwrap(),
+            RData::Mx {
+                preference: 10,
+                exchange: "mail.example.com".into()
+            }
+        );
+        assert!(RData::from_text(rtype::A, "::1").is_err());
+        assert_eq!(rtype::from_name("https"), Some(rtype::HTTPS));
+        assert_eq!(rtype::from_name("TYPE99"), Some(99));
+    }
+}
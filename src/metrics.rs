@@ -0,0 +1,423 @@
+//! Prometheus 文本格式的计数器与直方图，抓取时现场渲染
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// 上游与整体耗时的直方图分桶（秒）
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// 标签值 → 计数，标签取值由配置与查询决定，数量有限
+#[derive(Debug, Default)]
+pub struct Labeled(Mutex<BTreeMap<String, u64>>);
+
+impl Labeled {
+    pub fn inc(&self, label: &str) {
+        let mut map = self.0.lock().expect("[E] metrics lock");
+        match map.get_mut(label) {
+            Some(count) => *count += 1,
+            None => {
+                map.insert(label.into(), 1);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.0.lock().expect("[E] metrics lock").clone()
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        for (value, count) in self.0.lock().expect("[E] metrics lock").iter() {
+            let _ = writeln!(out, "{name}{{{label}=\"{}\"}} {count}", escape(value));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        // 只计入第一个容纳它的桶，渲染时累加
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// `labels` 为已渲染的标签，如 `server="8.8.8.8:53"`
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let with_le = |le: &str| match labels {
+            "" => format!("{{le=\"{le}\"}}"),
+            _ => format!("{{{labels},le=\"{le}\"}}"),
+        };
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{} {cumulative}",
+                with_le(&le.to_string())
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let labels = match labels {
+            "" => String::new(),
+            _ => format!("{{{labels}}}"),
+        };
+        let _ = writeln!(out, "{name}_bucket{} {count}", with_le("+Inf"));
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+/// 从服务器统计中取出某个计数器
+type Counter = fn(&ServerMetrics) -> &AtomicU64;
+
+/// 单个上游服务器的统计，由 `Dns` 更新
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub cache_evictions: AtomicU64,
+    pub timeouts: AtomicU64,
+    pub send_errors: AtomicU64,
+    /// 未通过投毒检查而丢弃的应答
+    pub dropped_poisoned: AtomicU64,
+    /// 找不到对应请求（已超时或伪造）而丢弃的应答
+    pub dropped_unmatched: AtomicU64,
+    /// 等待应答的请求数
+    pub pending: AtomicU64,
+    pub latency: Histogram,
+}
+
+/// 管理接口 `/stats` 的快照
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub queries: BTreeMap<String, u64>,
+    pub qtypes: BTreeMap<String, u64>,
+    pub rcodes: BTreeMap<String, u64>,
+    pub errors: BTreeMap<String, u64>,
+    pub restarts: BTreeMap<String, u64>,
+    pub servers: Vec<ServerStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerStats {
+    pub upstream: String,
+    pub server: String,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_evictions: u64,
+    pub timeouts: u64,
+    pub send_errors: u64,
+    pub dropped_poisoned: u64,
+    pub dropped_unmatched: u64,
+    pub pending: u64,
+    /// 收到应答的次数
+    pub responses: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// 按命中的规则，未命中为 default
+    pub queries: Labeled,
+    pub qtypes: Labeled,
+    pub rcodes: Labeled,
+    pub duration: Histogram,
+    /// 服务路径上处理掉的错误，按种类
+    pub errors: Arc<Labeled>,
+    /// 后台任务的重启次数，按任务名
+    pub restarts: Arc<Labeled>,
+    /// (上游组, 服务器地址, 统计)
+    servers: Mutex<Vec<(String, String, Arc<ServerMetrics>)>>,
+    /// (规则名, 域名条数)
+    rule_entries: Mutex<Vec<(String, usize)>>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+impl Metrics {
+    pub fn register_server(&self, upstream: &str, server: &str) -> Arc<ServerMetrics> {
+        let metrics = Arc::new(ServerMetrics::default());
+        self.servers.lock().expect("[E] metrics lock").push((
+            upstream.into(),
+            server.into(),
+            metrics.clone(),
+        ));
+        metrics
+    }
+
+    pub fn unregister_server(&self, metrics: &Arc<ServerMetrics>) {
+        self.servers
+            .lock()
+            .expect("[E] metrics lock")
+            .retain(|(_, _, m)| !Arc::ptr_eq(m, metrics));
+    }
+
+    pub fn set_rule_entries(&self, rules: Vec<(String, usize)>) {
+        *self.rule_entries.lock().expect("[E] metrics lock") = rules;
+    }
+
+    pub fn stats(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let servers = self
+            .servers
+            .lock()
+            .expect("[E] metrics lock")
+            .iter()
+            .map(|(upstream, server, m)| ServerStats {
+                upstream: upstream.clone(),
+                server: server.clone(),
+                cache_hits: load(&m.cache_hits),
+                cache_misses: load(&m.cache_misses),
+                cache_evictions: load(&m.cache_evictions),
+                timeouts: load(&m.timeouts),
+                send_errors: load(&m.send_errors),
+                dropped_poisoned: load(&m.dropped_poisoned),
+                dropped_unmatched: load(&m.dropped_unmatched),
+                pending: load(&m.pending),
+                responses: load(&m.latency.count),
+            })
+            .collect();
+
+        Stats {
+            queries: self.queries.snapshot(),
+            qtypes: self.qtypes.snapshot(),
+            rcodes: self.rcodes.snapshot(),
+            errors: self.errors.snapshot(),
+            restarts: self.restarts.snapshot(),
+            servers,
+        }
+    }
+
+    /// Prometheus 文本格式 0.0.4
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(4096);
+
+        header(
+            &mut out,
+            "fakedns_queries_total",
+            "counter",
+            "Queries by routing rule.",
+        );
+        self.queries
+            .render(&mut out, "fakedns_queries_total", "route");
+        header(
+            &mut out,
+            "fakedns_queries_by_type_total",
+            "counter",
+            "Queries by type.",
+        );
+        self.qtypes
+            .render(&mut out, "fakedns_queries_by_type_total", "qtype");
+        header(
+            &mut out,
+            "fakedns_responses_total",
+            "counter",
+            "Responses by rcode.",
+        );
+        self.rcodes
+            .render(&mut out, "fakedns_responses_total", "rcode");
+        header(
+            &mut out,
+            "fakedns_request_duration_seconds",
+            "histogram",
+            "Time from receiving a query to answering it.",
+        );
+        self.duration
+            .render(&mut out, "fakedns_request_duration_seconds", "");
+        header(
+            &mut out,
+            "fakedns_errors_total",
+            "counter",
+            "Errors handled while serving queries, by kind.",
+        );
+        self.errors.render(&mut out, "fakedns_errors_total", "kind");
+        header(
+            &mut out,
+            "fakedns_task_restarts_total",
+            "counter",
+            "Background tasks restarted after failing.",
+        );
+        self.restarts
+            .render(&mut out, "fakedns_task_restarts_total", "task");
+
+        let servers = self.servers.lock().expect("[E] metrics lock");
+        let counters: [(&str, &str, Counter); 5] = [
+            ("fakedns_cache_hits_total", "Upstream cache hits.", |m| {
+                &m.cache_hits
+            }),
+            (
+                "fakedns_cache_misses_total",
+                "Upstream cache misses.",
+                |m| &m.cache_misses,
+            ),
+            (
+                "fakedns_cache_evictions_total",
+                "Upstream cache entries evicted when full.",
+                |m| &m.cache_evictions,
+            ),
+            (
+                "fakedns_upstream_timeouts_total",
+                "Upstream queries that timed out.",
+                |m| &m.timeouts,
+            ),
+            (
+                "fakedns_upstream_send_errors_total",
+                "Upstream queries that failed to send.",
+                |m| &m.send_errors,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            header(&mut out, name, "counter", help);
+            for (upstream, server, metrics) in servers.iter() {
+                let _ = writeln!(
+                    out,
+                    "{name}{{upstream=\"{}\",server=\"{server}\"}} {}",
+                    escape(upstream),
+                    counter(metrics).load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "fakedns_upstream_dropped_total",
+            "counter",
+            "Upstream packets dropped as poisoned or unmatched.",
+        );
+        for (upstream, server, metrics) in servers.iter() {
+            for (reason, counter) in [
+                ("poisoned", &metrics.dropped_poisoned),
+                ("unmatched", &metrics.dropped_unmatched),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "fakedns_upstream_dropped_total{{upstream=\"{}\",server=\"{server}\",reason=\"{reason}\"}} {}",
+                    escape(upstream),
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "fakedns_upstream_pending",
+            "gauge",
+            "Upstream queries waiting for a response.",
+        );
+        for (upstream, server, metrics) in servers.iter() {
+            let _ = writeln!(
+                out,
+                "fakedns_upstream_pending{{upstream=\"{}\",server=\"{server}\"}} {}",
+                escape(upstream),
+                metrics.pending.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "fakedns_upstream_latency_seconds",
+            "histogram",
+            "Upstream response latency.",
+        );
+        for (upstream, server, metrics) in servers.iter() {
+            let labels = format!("upstream=\"{}\",server=\"{server}\"", escape(upstream));
+            metrics
+                .latency
+                .render(&mut out, "fakedns_upstream_latency_seconds", &labels);
+        }
+        drop(servers);
+
+        header(
+            &mut out,
+            "fakedns_rule_entries",
+            "gauge",
+            "Domains loaded per rule.",
+        );
+        for (rule, entries) in self.rule_entries.lock().expect("[E] metrics lock").iter() {
+            let _ = writeln!(
+                out,
+                "fakedns_rule_entries{{rule=\"{}\"}} {entries}",
+                escape(rule)
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_metrics() {
+        let metrics = Metrics::default();
+        metrics.queries.inc("proxy");
+        metrics.queries.inc("proxy");
+        metrics.queries.inc("say \"hi\"");
+        let server = metrics.register_server("direct", "223.5.5.5:53");
+        server.cache_hits.fetch_add(3, Ordering::Relaxed);
+        server.latency.observe(Duration::from_millis(3));
+        server.latency.observe(Duration::from_millis(30));
+        server.latency.observe(Duration::from_secs(2));
+        metrics.set_rule_entries(vec![("proxy".into(), 42)]);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE fakedns_queries_total counter\n"));
+        assert!(text.contains("fakedns_queries_total{route=\"proxy\"} 2\n"));
+        assert!(text.contains("fakedns_queries_total{route=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text
+            .contains("fakedns_cache_hits_total{upstream=\"direct\",server=\"223.5.5.5:53\"} 3\n"));
+        let bucket = |le: &str| {
+            format!("fakedns_upstream_latency_seconds_bucket{{upstream=\"direct\",server=\"223.5.5.5:53\",le=\"{le}\"}}")
+        };
+        assert!(text.contains(&format!("{} 0\n", bucket("0.0025"))));
+        assert!(text.contains(&format!("{} 1\n", bucket("0.005"))));
+        assert!(text.contains(&format!("{} 2\n", bucket("1"))));
+        assert!(text.contains(&format!("{} 3\n", bucket("+Inf"))));
+        assert!(text.contains(
+            "fakedns_upstream_latency_seconds_count{upstream=\"direct\",server=\"223.5.5.5:53\"} 3\n"
+        ));
+        assert!(text.contains("fakedns_request_duration_seconds_bucket{le=\"+Inf\"} 0\n"));
+        assert!(text.contains("fakedns_request_duration_seconds_count 0\n"));
+        assert!(text.contains("fakedns_rule_entries{rule=\"proxy\"} 42\n"));
+    }
+}
@@ -0,0 +1,632 @@
+//! 把规则命中域名的解析结果写入 nftables 集合，类似 dnsmasq 的 `nftset=`
+//!
+//! 直接通过 NETLINK_NETFILTER 发送 NEWSETELEM 批量消息，元素带 TTL 作为超时，
+//! 集合需以 `flags timeout` 创建。dry-run 模式把同样的更新以 nft 语法写入文件。
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    mem::size_of,
+    net::IpAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    str::FromStr,
+    sync::Arc,
+    thread,
+};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use crate::config::NftSetGlobalConfig;
+
+/// 待写入的更新数上限，写入跟不上时丢弃新的更新而不阻塞应答
+const MAX_PENDING: usize = 4096;
+/// 单个批量消息最多包含的元素数
+const MAX_BATCH: usize = 256;
+
+const NETLINK_NETFILTER: i32 = 12;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_CREATE: u16 = 0x400;
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_TIMEOUT: u16 = 4;
+const NFTA_DATA_VALUE: u16 = 1;
+
+/// 目标集合，写法同 dnsmasq：`4#inet#fw#proxy4`，`6#inet#fw#proxy6`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NftSet {
+    pub ipv6: bool,
+    pub family: String,
+    pub table: String,
+    pub set: String,
+}
+
+fn family_number(family: &str) -> Option<u8> {
+    match family {
+        "inet" => Some(1),
+        "ip" => Some(2),
+        "arp" => Some(3),
+        "netdev" => Some(5),
+        "bridge" => Some(7),
+        "ip6" => Some(10),
+        _ => None,
+    }
+}
+
+impl FromStr for NftSet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid nftset {s:?}"));
+        let fields: Vec<&str> = s.split('#').collect();
+        let [ip, family, table, set] = fields[..] else {
+            return Err(invalid());
+        };
+        let ipv6 = match ip {
+            "4" => false,
+            "6" => true,
+            _ => return Err(invalid()),
+        };
+        if family_number(family).is_none() || table.is_empty() || set.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            ipv6,
+            family: family.into(),
+            table: table.into(),
+            set: set.into(),
+        })
+    }
+}
+
+impl TryFrom<String> for NftSet {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<NftSet> for String {
+    fn from(value: NftSet) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for NftSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ip = if self.ipv6 { 6 } else { 4 };
+        write!(f, "{ip}#{}#{}#{}", self.family, self.table, self.set)
+    }
+}
+
+impl NftSet {
+    pub fn accepts(&self, ip: IpAddr) -> bool {
+        ip.is_ipv6() == self.ipv6
+    }
+}
+
+struct Update {
+    set: Arc<NftSet>,
+    ip: IpAddr,
+    ttl: u32,
+}
+
+/// netlink 消息构造
+#[derive(Default)]
+struct Buffer {
+    buf: Vec<u8>,
+    /// 未闭合的消息与嵌套属性的起始位置
+    starts: Vec<usize>,
+}
+
+impl Buffer {
+    fn pad(&mut self) {
+        while !self.buf.len().is_multiple_of(4) {
+            self.buf.push(0);
+        }
+    }
+
+    fn begin_message(&mut self, kind: u16, flags: u16, seq: u32, family: u8, res_id: u16) {
+        self.starts.push(self.buf.len());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg
+        self.buf.push(family);
+        self.buf.push(0);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+    }
+
+    fn end_message(&mut self) {
+        let start = self.starts.pop().expect("[E] unbalanced netlink message");
+        let len = (self.buf.len() - start) as u32;
+        self.buf[start..start + 4].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) {
+        self.buf
+            .extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.pad();
+    }
+
+    fn attr_str(&mut self, kind: u16, s: &str) {
+        let mut data = Vec::with_capacity(s.len() + 1);
+        data.extend_from_slice(s.as_bytes());
+        data.push(0);
+        self.attr(kind, &data);
+    }
+
+    fn begin_nested(&mut self, kind: u16) {
+        self.starts.push(self.buf.len());
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+    }
+
+    fn end_nested(&mut self) {
+        let start = self.starts.pop().expect("[E] unbalanced netlink attribute");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+}
+
+/// 同一集合的更新合并为一条 NEWSETELEM，整体包在一个批量事务中；
+/// 返回消息内容与需要确认的消息数
+fn encode_batch(updates: &[Update], seq: &mut u32) -> (Vec<u8>, usize) {
+    let mut b = Buffer::default();
+    let mut acks = 0;
+
+    b.begin_message(
+        NFNL_MSG_BATCH_BEGIN,
+        NLM_F_REQUEST,
+        *seq,
+        0,
+        NFNL_SUBSYS_NFTABLES,
+    );
+    b.end_message();
+    *seq += 1;
+
+    let mut sets: Vec<&Arc<NftSet>> = Vec::new();
+    for update in updates {
+        if !sets.contains(&&update.set) {
+            sets.push(&update.set);
+        }
+    }
+
+    for set in sets {
+        b.begin_message(
+            NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWSETELEM,
+            NLM_F_REQUEST | NLM_F_CREATE | NLM_F_ACK,
+            *seq,
+            family_number(&set.family).unwrap_or_default(),
+            0,
+        );
+        b.attr_str(NFTA_SET_ELEM_LIST_TABLE, &set.table);
+        b.attr_str(NFTA_SET_ELEM_LIST_SET, &set.set);
+        b.begin_nested(NFTA_SET_ELEM_LIST_ELEMENTS);
+        for update in updates.iter().filter(|u| &u.set == set) {
+            b.begin_nested(NFTA_LIST_ELEM);
+            b.begin_nested(NFTA_SET_ELEM_KEY);
+            match update.ip {
+                IpAddr::V4(ip) => b.attr(NFTA_DATA_VALUE, &ip.octets()),
+                IpAddr::V6(ip) => b.attr(NFTA_DATA_VALUE, &ip.octets()),
+            }
+            b.end_nested();
+            let timeout = update.ttl.max(1) as u64 * 1000;
+            b.attr(NFTA_SET_ELEM_TIMEOUT, &timeout.to_be_bytes());
+            b.end_nested();
+        }
+        b.end_nested();
+        b.end_message();
+        *seq += 1;
+        acks += 1;
+    }
+
+    b.begin_message(
+        NFNL_MSG_BATCH_END,
+        NLM_F_REQUEST,
+        *seq,
+        0,
+        NFNL_SUBSYS_NFTABLES,
+    );
+    b.end_message();
+    *seq += 1;
+
+    (b.buf, acks)
+}
+
+/// 阻塞的 NETLINK_NETFILTER socket，只在写入线程中使用
+struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    fn open() -> Result<Self> {
+        // SAFETY: 调用成功时返回新的 fd，由 OwnedFd 接管
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                NETLINK_NETFILTER,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // 内核出错时可能不回复全部确认，设置接收超时避免写入线程卡住
+        let timeout = libc::timeval {
+            tv_sec: 1,
+            tv_usec: 0,
+        };
+        // SAFETY: 传入的指针与长度对应同一个 timeval
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                size_of::<libc::timeval>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Self { fd, seq: 1 })
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        // SAFETY: message 与 addr 在调用期间有效
+        let ret = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// 读取 n 个确认，返回第一个错误
+    fn recv_acks(&mut self, mut n: usize) -> Result<()> {
+        let mut buf = [0u8; 8192];
+        let mut first_error = None;
+
+        while n > 0 {
+            // SAFETY: buf 在调用期间有效
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let mut data = &buf[..len as usize];
+            while data.len() >= 16 {
+                let msg_len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+                if msg_len < 16 || msg_len > data.len() {
+                    break;
+                }
+                if kind == NLMSG_ERROR && msg_len >= 20 {
+                    let errno = i32::from_ne_bytes(data[16..20].try_into().unwrap());
+                    if errno != 0 && first_error.is_none() {
+                        first_error = Some(Error::from_raw_os_error(-errno));
+                    }
+                    n = n.saturating_sub(1);
+                }
+                data = &data[(msg_len + 3) & !3..];
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, updates: &[Update]) -> Result<()> {
+        let (message, acks) = encode_batch(updates, &mut self.seq);
+        self.send(&message)?;
+        self.recv_acks(acks)
+    }
+}
+
+enum Sink {
+    Netlink(Netlink),
+    DryRun(File),
+}
+
+impl Sink {
+    fn write(&mut self, updates: &[Update]) -> Result<()> {
+        match self {
+            Sink::Netlink(netlink) => netlink.write(updates),
+            Sink::DryRun(file) => {
+                for u in updates {
+                    writeln!(
+                        file,
+                        "add element {} {} {} {{ {} timeout {}s }}",
+                        u.set.family,
+                        u.set.table,
+                        u.set.set,
+                        u.ip,
+                        u.ttl.max(1)
+                    )?;
+                }
+                file.flush()
+            }
+        }
+    }
+}
+
+/// 更新经由通道交给独立线程批量写入，避免阻塞应答
+pub struct NftSetWriter {
+    tx: mpsc::Sender<Update>,
+}
+
+impl TryFrom<&NftSetGlobalConfig> for NftSetWriter {
+    type Error = Error;
+
+    fn try_from(config: &NftSetGlobalConfig) -> Result<Self> {
+        let mut sink = match &config.dry_run {
+            Some(path) => Sink::DryRun(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Sink::Netlink(Netlink::open()?),
+        };
+
+        let (tx, mut rx) = mpsc::channel::<Update>(MAX_PENDING);
+        thread::Builder::new()
+            .name("nftset".into())
+            .spawn(move || {
+                let mut batch = Vec::with_capacity(MAX_BATCH);
+                while let Some(update) = rx.blocking_recv() {
+                    batch.push(update);
+                    while batch.len() < MAX_BATCH {
+                        match rx.try_recv() {
+                            Ok(update) => batch.push(update),
+                            Err(_) => break,
+                        }
+                    }
+                    if let Err(e) = sink.write(&batch) {
+                        error!(updates = batch.len(), error = ?e, "nftset write");
+                    }
+                    batch.clear();
+                }
+            })?;
+
+        Ok(Self { tx })
+    }
+}
+
+impl NftSetWriter {
+    /// 把地址加入规则配置的、地址族匹配的集合
+    pub fn add<I>(&self, sets: &[Arc<NftSet>], ips: I)
+    where
+        I: IntoIterator<Item = (IpAddr, u32)>,
+    {
+        for (ip, ttl) in ips {
+            for set in sets.iter().filter(|s| s.accepts(ip)) {
+                let update = Update {
+                    set: set.clone(),
+                    ip,
+                    ttl,
+                };
+                if self.tx.try_send(update).is_err() {
+                    warn!(%ip, "nftset queue full, drop");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process::Command};
+
+    const NFT_MSG_NEWTABLE: u16 = 0;
+    const NFT_MSG_NEWSET: u16 = 9;
+    const NLM_F_EXCL: u16 = 0x200;
+
+    fn update(set: &Arc<NftSet>, ip: &str, ttl: u32) -> Update {
+        Update {
+            set: set.clone(),
+            ip: ip.parse().unwrap(),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn it_work_parse() {
+        let set: NftSet = "6#inet#fw#proxy6".parse().unwrap();
+        assert!(set.ipv6);
+        assert_eq!(set.to_string(), "6#inet#fw#proxy6");
+        assert!(set.accepts("::1".parse().unwrap()));
+        assert!(!set.accepts("1.1.1.1".parse().unwrap()));
+
+        assert!("4#inet#fw".parse::<NftSet>().is_err());
+        assert!("5#inet#fw#s".parse::<NftSet>().is_err());
+        assert!("4#foo#fw#s".parse::<NftSet>().is_err());
+    }
+
+    #[test]
+    fn it_work_encode() {
+        let v4 = Arc::new("4#inet#fw#p4".parse().unwrap());
+        let v6 = Arc::new("6#inet#fw#p6".parse().unwrap());
+        let updates = [
+            update(&v4, "1.2.3.4", 60),
+            update(&v6, "2001:db8::1", 0),
+            update(&v4, "5.6.7.8", 30),
+        ];
+        let mut seq = 7;
+        let (message, acks) = encode_batch(&updates, &mut seq);
+        assert_eq!(acks, 2);
+        assert_eq!(seq, 11);
+        assert_eq!(message.len() % 4, 0);
+
+        // 逐条检查消息头：batch begin、两个集合、batch end
+        let mut kinds = Vec::new();
+        let mut data = &message[..];
+        while !data.is_empty() {
+            let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+            kinds.push(u16::from_ne_bytes(data[4..6].try_into().unwrap()));
+            data = &data[len..];
+        }
+        let newsetelem = NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWSETELEM;
+        assert_eq!(
+            kinds,
+            [
+                NFNL_MSG_BATCH_BEGIN,
+                newsetelem,
+                newsetelem,
+                NFNL_MSG_BATCH_END
+            ]
+        );
+
+        // IPv4 集合包含两个元素，超时为大端毫秒
+        let timeout = 60_000u64.to_be_bytes();
+        assert!(message.windows(8).any(|w| w == timeout));
+        assert!(message.windows(4).any(|w| w == [5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn it_work_dry_run() {
+        let path = env::temp_dir().join(format!("fakedns-nftset-{}.txt", std::process::id()));
+        let set = Arc::new("4#inet#fw#proxy4".parse().unwrap());
+        let mut sink = Sink::DryRun(File::create(&path).unwrap());
+        sink.write(&[update(&set, "1.2.3.4", 300)]).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "add element inet fw proxy4 { 1.2.3.4 timeout 300s }\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// 在测试中建表与集合（正式运行时由管理员预先创建）
+    fn create_set(netlink: &mut Netlink, set: &NftSet) -> Result<()> {
+        let family = family_number(&set.family).unwrap();
+        let mut b = Buffer::default();
+        let flags = NLM_F_REQUEST | NLM_F_CREATE | NLM_F_ACK;
+        b.begin_message(
+            NFNL_MSG_BATCH_BEGIN,
+            NLM_F_REQUEST,
+            1,
+            0,
+            NFNL_SUBSYS_NFTABLES,
+        );
+        b.end_message();
+
+        b.begin_message(
+            NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWTABLE,
+            flags,
+            2,
+            family,
+            0,
+        );
+        b.attr_str(1, &set.table);
+        b.end_message();
+
+        b.begin_message(
+            NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWSET,
+            flags,
+            3,
+            family,
+            0,
+        );
+        b.attr_str(1, &set.table);
+        b.attr_str(2, &set.set);
+        // flags timeout，ipv4_addr
+        b.attr(3, &0x10u32.to_be_bytes());
+        b.attr(4, &7u32.to_be_bytes());
+        b.attr(5, &4u32.to_be_bytes());
+        b.attr(10, &1u32.to_be_bytes());
+        b.end_message();
+
+        b.begin_message(
+            NFNL_MSG_BATCH_END,
+            NLM_F_REQUEST,
+            4,
+            0,
+            NFNL_SUBSYS_NFTABLES,
+        );
+        b.end_message();
+
+        netlink.send(&b.buf)?;
+        netlink.recv_acks(2)
+    }
+
+    /// 需要 nf_tables：在 `unshare -rn` 创建的无特权网络命名空间中重新运行自身
+    #[test]
+    #[ignore = "needs unshare and nf_tables"]
+    fn it_work_netlink() {
+        if env::var_os("FAKEDNS_NETNS").is_none() {
+            let exe = env::current_exe().unwrap();
+            let status = Command::new("unshare")
+                .args(["-rn"])
+                .arg(exe)
+                .args(["--exact", "--ignored", "nftset::tests::it_work_netlink"])
+                .env("FAKEDNS_NETNS", "1")
+                .status()
+                .expect("[E] unshare");
+            assert!(status.success());
+            return;
+        }
+
+        let set: Arc<NftSet> = Arc::new("4#inet#fakedns#proxy4".parse().unwrap());
+        let mut netlink = Netlink::open().unwrap();
+        create_set(&mut netlink, &set).expect("[E] nf_tables");
+
+        netlink.write(&[update(&set, "1.2.3.4", 60)]).unwrap();
+        // 重复添加不报错
+        netlink.write(&[update(&set, "1.2.3.4", 60)]).unwrap();
+
+        // 以 EXCL 再次添加返回 EEXIST，说明元素已在集合中
+        let (mut message, acks) = encode_batch(&[update(&set, "1.2.3.4", 60)], &mut netlink.seq);
+        let flags_at = 20 + 6;
+        let flags = u16::from_ne_bytes(message[flags_at..flags_at + 2].try_into().unwrap());
+        message[flags_at..flags_at + 2].copy_from_slice(&(flags | NLM_F_EXCL).to_ne_bytes());
+        netlink.send(&message).unwrap();
+        let e = netlink.recv_acks(acks).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+
+        // 不存在的集合
+        let missing = Arc::new("4#inet#fakedns#missing".parse().unwrap());
+        assert!(netlink.write(&[update(&missing, "1.2.3.4", 60)]).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! 临时停用拦截规则，可以针对全部或单个客户端，到期自动恢复
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct State {
+    global: Option<Instant>,
+    clients: HashMap<IpAddr, Instant>,
+}
+
+/// 剩余秒数
+#[derive(Debug, Serialize)]
+pub struct PauseStatus {
+    pub global: Option<u64>,
+    pub clients: BTreeMap<IpAddr, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct BlockPause(Mutex<State>);
+
+impl BlockPause {
+    /// `client` 为空时对全部客户端生效，重复暂停以最后一次为准；时长过长时返回 `InvalidInput`
+    pub fn pause(&self, client: Option<IpAddr>, duration: Duration) -> Result<()> {
+        let now = Instant::now();
+        let until = now
+            .checked_add(duration)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "duration too large"))?;
+        let mut state = self.0.lock().expect("[E] pause lock");
+        state.clients.retain(|_, until| *until > now);
+        match client {
+            Some(ip) => {
+                state.clients.insert(ip, until);
+            }
+            None => state.global = Some(until),
+        }
+        Ok(())
+    }
+
+    /// 返回是否有暂停被取消
+    pub fn resume(&self, client: Option<IpAddr>) -> bool {
+        let mut state = self.0.lock().expect("[E] pause lock");
+        match client {
+            Some(ip) => state.clients.remove(&ip).is_some(),
+            None => state.global.take().is_some(),
+        }
+    }
+
+    pub fn is_paused(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let state = self.0.lock().expect("[E] pause lock");
+        state.global.is_some_and(|until| until > now)
+            || state.clients.get(&client).is_some_and(|until| *until > now)
+    }
+
+    pub fn status(&self) -> PauseStatus {
+        let now = Instant::now();
+        let remaining = |until: Instant| until.checked_duration_since(now).map(|d| d.as_secs());
+        let state = self.0.lock().expect("[E] pause lock");
+        PauseStatus {
+            global: state.global.and_then(remaining),
+            clients: state
+                .clients
+                .iter()
+                .filter_map(|(ip, until)| remaining(*until).map(|secs| (*ip, secs)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_pause() {
+        let pause = BlockPause::default();
+        let dev: IpAddr = "192.168.1.20".parse().unwrap();
+        let other: IpAddr = "192.168.1.21".parse().unwrap();
+        assert!(!pause.is_paused(dev));
+
+        pause.pause(Some(dev), Duration::from_secs(60)).unwrap();
+        assert!(pause.is_paused(dev));
+        assert!(!pause.is_paused(other));
+        assert!(pause.status().clients[&dev] <= 60);
+
+        pause.pause(None, Duration::ZERO).unwrap();
+        assert!(!pause.is_paused(other));
+        pause.pause(None, Duration::from_secs(60)).unwrap();
+        assert!(pause.is_paused(other));
+        let e = pause
+            .pause(None, Duration::from_secs(u64::MAX))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(pause.is_paused(other));
+
+        assert!(pause.resume(None));
+        assert!(!pause.resume(None));
+        assert!(pause.resume(Some(dev)));
+        assert!(!pause.is_paused(dev));
+    }
+}
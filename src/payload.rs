@@ -13,30 +13,53 @@ impl AsRef<[u8]> for Payload {
     }
 }
 
+/// 客户端与上游发来的数据不可信，以下读写在数据过短或格式错误时都不会 panic
 impl Payload {
-    fn get(&self, index: usize) -> u8 {
-        *unsafe { self.as_ref().get_unchecked(index) }
+    pub fn id(&self) -> u16 {
+        match self.0.get(..2) {
+            Some(b) => (b[0] as u16) << 8 | b[1] as u16,
+            None => 0,
+        }
     }
 
-    pub fn id(&self) -> u16 {
-        let (a, b) = (self.get(0) as u16, self.get(1) as u16);
-        a << 8 | b
+    pub fn set_id(&mut self, id: u16) {
+        if let Some(b) = self.0.get_mut(..2) {
+            b.copy_from_slice(&id.to_be_bytes());
+        }
+    }
+
+    pub fn rcode(&self) -> u8 {
+        self.0.get(3).map_or(0, |b| b & 0x0f)
+    }
+
+    /// 问题的查询类型，紧跟在域名结束位置之后
+    pub fn qtype(&self) -> u16 {
+        let (_, offset) = self.domain();
+        match self.0.get(offset + 1..offset + 3) {
+            Some(b) => (b[0] as u16) << 8 | b[1] as u16,
+            None => 0,
+        }
     }
 
+    /// 问题中的域名（逆序）及结束位置，遇到截断或压缩指针时到此为止
     pub fn domain(&self) -> (Vec<&[u8]>, usize) {
         let mut domain: Vec<&[u8]> = Vec::new();
         // default offset = 12
         let mut current_offset = 12;
 
-        loop {
-            let label_length = self.0[current_offset] as usize;
-
-            if label_length == 0 {
+        while let Some(&label_length) = self.0.get(current_offset) {
+            if label_length == 0 || label_length & 0xc0 != 0 {
                 break;
             }
+            let label_length = label_length as usize;
 
             // Regular label
-            let label = &self.0[current_offset + 1..current_offset + 1 + label_length];
+            let Some(label) = self
+                .0
+                .get(current_offset + 1..current_offset + 1 + label_length)
+            else {
+                break;
+            };
             domain.push(label);
 
             // Move to the next label
@@ -49,13 +72,8 @@ impl Payload {
     }
 
     pub fn servfail(&mut self) {
-        debug_assert!(self.0.len() >= 12);
-
-        unsafe {
-            let p = self.0.as_mut_ptr();
-
-            *p.add(2) = 0x81;
-            *p.add(3) = 0x82;
+        if let Some(flags) = self.0.get_mut(2..4) {
+            flags.copy_from_slice(&[0x81, 0x82]);
         }
     }
 }
@@ -85,5 +103,17 @@ mod tests {
         let domain2: Vec<&[u8]> = vec![b"top", b"vlper", b"xr1"];
         assert_eq!(domain, domain2);
         assert_eq!(offset, 26);
+
+        // 截断、压缩指针与过短的数据
+        let truncated = Payload::from(&b[..20]);
+        assert_eq!(truncated.domain().1, 16);
+        let mut pointer = Payload::from(&[0u8; 12][..]);
+        pointer.0.extend([0xc0, 0x0c]);
+        assert!(pointer.domain().0.is_empty());
+        let mut short = Payload::from(&[0x12u8][..]);
+        assert_eq!(short.id(), 0);
+        short.set_id(1);
+        short.servfail();
+        assert_eq!(short.0, [0x12]);
     }
 }
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use crate::{config::UpstreamConfig, message::Message, payload::Payload, trie::IpTrie};
+
+/// 上游应答的防污染检查
+#[derive(Debug, Default)]
+pub struct PoisonGuard {
+    bogus_ip: IpTrie,
+    require_edns: bool,
+    /// 首个合法应答到达后的等待窗口，为零时立即返回
+    pub wait: Duration,
+}
+
+impl From<&UpstreamConfig> for PoisonGuard {
+    fn from(config: &UpstreamConfig) -> Self {
+        let mut bogus_ip = IpTrie::default();
+        for cidr in &config.bogus_ip {
+            bogus_ip.insert(*cidr);
+        }
+
+        Self {
+            bogus_ip,
+            require_edns: config.require_edns,
+            wait: Duration::from_millis(config.wait),
+        }
+    }
+}
+
+impl PoisonGuard {
+    fn enabled(&self) -> bool {
+        !self.bogus_ip.is_empty() || self.require_edns
+    }
+
+    /// 请求是否带 EDNS，决定应答是否必须带 OPT
+    pub fn expects_edns(&self, query: &Payload) -> bool {
+        self.require_edns
+            && Message::try_from(query)
+                .map(|m| m.edns().is_some())
+                .unwrap_or_default()
+    }
+
+    /// 检查应答，返回丢弃原因
+    pub fn check(&self, expect_edns: bool, response: &Payload) -> Result<(), String> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let message = Message::try_from(response).map_err(|e| format!("malformed {e}"))?;
+
+        if expect_edns && message.edns().is_none() {
+            return Err("missing EDNS OPT".into());
+        }
+        if let Some((ip, _)) = message
+            .answer_ips()
+            .find(|(ip, _)| self.bogus_ip.contains(*ip))
+        {
+            return Err(format!("bogus ip {ip}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{rtype, RData, Record};
+
+    fn message(ip: &str, edns: bool) -> Payload {
+        let mut message = Message::query("example.com", rtype::A);
+        message.answers.push(Record::new(
+            "example.com",
+            60,
+            RData::A(ip.parse().unwrap()),
+        ));
+        if edns {
+            message
+                .additionals
+                .push(Record::with_type("", rtype::OPT, 0, RData::Raw(Vec::new())));
+        }
+        Payload::try_from(&message).unwrap()
+    }
+
+    #[test]
+    fn it_work_check() {
+        let guard = PoisonGuard::from(&UpstreamConfig {
+            bogus_ip: vec!["243.185.187.39".parse().unwrap()],
+            require_edns: true,
+            ..Default::default()
+        });
+
+        let query = message("0.0.0.0", true);
+        let edns = guard.expects_edns(&query);
+        assert!(edns);
+        assert!(!guard.expects_edns(&message("0.0.0.0", false)));
+
+        guard.check(edns, &message("93.184.216.34", true)).unwrap();
+        assert!(guard.check(edns, &message("93.184.216.34", false)).is_err());
+        assert!(guard.check(false, &message("93.184.216.34", false)).is_ok());
+        assert_eq!(
+            guard.check(edns, &message("243.185.187.39", true)),
+            Err("bogus ip 243.185.187.39".into())
+        );
+        assert!(PoisonGuard::default()
+            .check(true, &message("243.185.187.39", false))
+            .is_ok());
+    }
+}
@@ -0,0 +1,377 @@
+//! 查询日志：每个应答一条记录，经由通道交给独立线程写入可轮转的文件
+use serde::Serialize;
+use std::{
+    fs::{rename, File, OpenOptions},
+    io::{BufWriter, Result, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::spawn_blocking};
+use tracing::{error, warn};
+
+use crate::{
+    config::{QueryLogConfig, QueryLogFormat},
+    message::{rcode, rtype, Message},
+    payload::Payload,
+    trie::IpTrie,
+};
+
+/// 待写入的记录数上限，写入跟不上时丢弃新的记录而不阻塞应答
+const MAX_PENDING: usize = 8192;
+/// 每丢弃这么多条记录告警一次
+const DROP_WARN_EVERY: u64 = 1000;
+
+/// 应答完成时收集的信息，地址与 rcode 在写入线程中解析
+#[derive(Debug)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub qname: String,
+    pub qtype: u16,
+    pub rule: String,
+    pub upstream: Option<String>,
+    pub response: Payload,
+    pub cached: bool,
+    pub latency: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct Line<'a> {
+    time: String,
+    client: SocketAddr,
+    qname: &'a str,
+    qtype: String,
+    rule: &'a str,
+    upstream: Option<&'a str>,
+    rcode: String,
+    answers: Vec<IpAddr>,
+    cached: bool,
+    latency_ms: f64,
+}
+
+impl<'a> From<&'a Entry> for Line<'a> {
+    fn from(entry: &'a Entry) -> Self {
+        let (rcode, answers) = match Message::try_from(&entry.response) {
+            Ok(message) => (
+                message.rcode(),
+                message.answer_ips().map(|(ip, _)| ip).collect(),
+            ),
+            Err(_) => (entry.response.rcode(), Vec::new()),
+        };
+        Self {
+            time: rfc3339(entry.time),
+            client: entry.client,
+            qname: &entry.qname,
+            qtype: rtype::name(entry.qtype),
+            rule: &entry.rule,
+            upstream: entry.upstream.as_deref(),
+            rcode: rcode::name(rcode),
+            answers,
+            cached: entry.cached,
+            latency_ms: entry.latency.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+impl Line<'_> {
+    fn write(&self, format: QueryLogFormat, out: &mut impl Write) -> Result<()> {
+        match format {
+            QueryLogFormat::Json => {
+                serde_json::to_writer(&mut *out, self)?;
+                writeln!(out)
+            }
+            QueryLogFormat::Text => {
+                let answers: Vec<String> = self.answers.iter().map(|ip| ip.to_string()).collect();
+                writeln!(
+                    out,
+                    "{} {} {} {} rule={} upstream={} {} [{}]{} {:.1}ms",
+                    self.time,
+                    self.client,
+                    self.qname,
+                    self.qtype,
+                    self.rule,
+                    self.upstream.unwrap_or("-"),
+                    self.rcode,
+                    answers.join(","),
+                    if self.cached { " cached" } else { "" },
+                    self.latency_ms,
+                )
+            }
+        }
+    }
+}
+
+/// UTC 时间，精确到毫秒
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // 公历换算，见 http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+/// 超过大小上限时把 `path` 依次改名为 `path.1`、`path.2`……，最多保留 `max_files` 个
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.into(),
+            size: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            max_size,
+            max_files,
+        })
+    }
+
+    fn backup(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{i}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = self.backup(i);
+                if from.exists() {
+                    rename(&from, self.backup(i + 1))?;
+                }
+            }
+            rename(&self.path, self.backup(1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+pub struct QueryLog {
+    /// `None` 通知写入线程结束
+    tx: mpsc::Sender<Option<Entry>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    /// 为空时记录所有客户端
+    clients: IpTrie,
+    exclude_clients: IpTrie,
+    /// 每 `sample` 条记录一条
+    sample: u32,
+    counter: AtomicU32,
+    dropped: AtomicU64,
+}
+
+impl TryFrom<&QueryLogConfig> for QueryLog {
+    type Error = std::io::Error;
+
+    fn try_from(config: &QueryLogConfig) -> Result<Self> {
+        let mut out = RotatingFile::open(&config.path, config.max_size, config.max_files)?;
+        let format = config.format;
+
+        let (tx, mut rx) = mpsc::channel::<Option<Entry>>(MAX_PENDING);
+        let writer = thread::Builder::new()
+            .name("query-log".into())
+            .spawn(move || {
+                let mut line = Vec::with_capacity(256);
+                let mut closed = false;
+                while let Some(Some(entry)) = rx.blocking_recv() {
+                    let mut next = Some(entry);
+                    // 取空通道后再刷新，批量写入
+                    while let Some(entry) = next {
+                        line.clear();
+                        let result = Line::from(&entry)
+                            .write(format, &mut line)
+                            .and_then(|_| out.write_line(&line));
+                        if let Err(e) = result {
+                            error!(error = ?e, "query log write");
+                        }
+                        next = match rx.try_recv() {
+                            Ok(Some(entry)) => Some(entry),
+                            Ok(None) => {
+                                closed = true;
+                                None
+                            }
+                            Err(_) => None,
+                        };
+                    }
+                    if let Err(e) = out.file.flush() {
+                        error!(error = ?e, "query log flush");
+                    }
+                    if closed {
+                        break;
+                    }
+                }
+            })?;
+
+        let mut clients = IpTrie::default();
+        config.clients.iter().for_each(|c| clients.insert(*c));
+        let mut exclude_clients = IpTrie::default();
+        config
+            .exclude_clients
+            .iter()
+            .for_each(|c| exclude_clients.insert(*c));
+
+        Ok(Self {
+            tx,
+            writer: Mutex::new(Some(writer)),
+            clients,
+            exclude_clients,
+            sample: config.sample.max(1),
+            counter: AtomicU32::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+}
+
+impl QueryLog {
+    /// 按客户端过滤与采样决定是否记录，在收集记录前调用
+    pub fn accepts(&self, client: IpAddr) -> bool {
+        if !self.clients.is_empty() && !self.clients.contains(client) {
+            return false;
+        }
+        if self.exclude_clients.contains(client) {
+            return false;
+        }
+        self.sample == 1
+            || self
+                .counter
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.sample)
+    }
+
+    pub fn record(&self, entry: Entry) {
+        if self.tx.try_send(Some(entry)).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % DROP_WARN_EVERY == 1 {
+                warn!(dropped, "query log queue full, drop");
+            }
+        }
+    }
+
+    /// 写完已排队的记录，等待写入线程退出
+    pub async fn close(&self) {
+        if self.tx.send(None).await.is_err() {
+            return;
+        }
+        let writer = self
+            .writer
+            .lock()
+            .expect("[E] query log writer lock")
+            .take();
+        if let Some(writer) = writer {
+            let _ = spawn_blocking(move || writer.join()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{RData, Record};
+    use std::fs::read_to_string;
+
+    #[test]
+    fn it_work_query_log() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56.789Z");
+
+        let mut reply = Message::try_from(
+            &[
+                0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+                b'c', b'o', b'm', 0, 0, 1, 0, 1,
+            ][..],
+        )
+        .unwrap();
+        reply.answers.push(Record::new(
+            "example.com",
+            60,
+            RData::A([1, 2, 3, 4].into()),
+        ));
+        let entry = Entry {
+            time,
+            client: "192.168.1.10:5353".parse().unwrap(),
+            qname: "example.com".into(),
+            qtype: rtype::A,
+            rule: "default".into(),
+            upstream: Some("direct".into()),
+            response: Payload::try_from(&reply).unwrap(),
+            cached: true,
+            latency: Duration::from_micros(1500),
+        };
+
+        let mut text = Vec::new();
+        Line::from(&entry)
+            .write(QueryLogFormat::Text, &mut text)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "2024-02-29T12:34:56.789Z 192.168.1.10:5353 example.com A rule=default upstream=direct NOERROR [1.2.3.4] cached 1.5ms\n"
+        );
+
+        let mut json = Vec::new();
+        Line::from(&entry)
+            .write(QueryLogFormat::Json, &mut json)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["answers"][0], "1.2.3.4");
+        assert_eq!(json["rcode"], "NOERROR");
+
+        let dir = std::env::temp_dir().join(format!("fakedns-querylog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("query.log");
+        let mut out = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            out.write_line(line.as_bytes()).unwrap();
+        }
+        out.file.flush().unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(read_to_string(out.backup(1)).unwrap(), "cccccc\n");
+        assert_eq!(read_to_string(out.backup(2)).unwrap(), "bbbbbb\n");
+        assert!(!out.backup(3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,943 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{spawn, task::spawn_blocking};
+use tracing::{debug, error, field::Empty, info, info_span, warn, Instrument, Span};
+
+use crate::{
+    chinadns::ChinaDns,
+    config::{Action, BlockMode, Config, RewriteAction, RuleConfig, StaticRecord, TtlConfig},
+    dnstap::Dnstap,
+    fakeip::FakeIp,
+    filter::ResponseFilter,
+    local::LocalRecords,
+    message::{rcode, rtype, ttl_offsets, Message, RData, Record},
+    metrics::Metrics,
+    nftset::NftSetWriter,
+    pause::BlockPause,
+    payload::Payload,
+    querylog::{Entry as QueryLogEntry, QueryLog},
+    reverse::{Entry, ObservedTable},
+    router::{Route, Router, Rule, TemporaryRules},
+    state,
+    topk::TopStats,
+    upstream::Upstream,
+    zone::Zones,
+};
+
+const BLOCK_TTL: u32 = 500;
+/// rewrite 合成的 CNAME 与补充地址的 TTL
+const REWRITE_TTL: u32 = 60;
+/// rewrite 目标可以再次命中 rewrite，限制深度避免循环
+const MAX_REWRITE_DEPTH: usize = 4;
+/// 未命中规则时报告的规则名
+const DEFAULT_RULE: &str = "default";
+
+tokio::task_local! {
+    /// 收集当前查询命中的规则与上游，供指标与查询日志使用
+    static TRACE: RefCell<Trace>;
+}
+
+#[derive(Debug, Default)]
+struct Trace {
+    rule: String,
+    upstream: Option<String>,
+    cached: bool,
+}
+
+/// 命中的规则写入 span 与查询日志，rewrite 的目标不覆盖原查询
+fn trace_rule(depth: usize, rule: &str) {
+    if depth == 0 {
+        Span::current().record("rule", rule);
+        let _ = TRACE.try_with(|t| t.borrow_mut().rule = rule.into());
+    }
+}
+
+fn trace_upstream(group: &str, cached: bool) {
+    Span::current().record("upstream", group);
+    let _ = TRACE.try_with(|t| {
+        let mut t = t.borrow_mut();
+        t.upstream = Some(group.into());
+        t.cached = cached;
+    });
+}
+
+pub struct Resolver {
+    /// 启动时的配置，管理接口修改上游后同步更新
+    config: RwLock<Config>,
+    started: Instant,
+    /// 重新加载规则文件时整体替换
+    router: RwLock<Arc<Router>>,
+    temporary: TemporaryRules,
+    pause: BlockPause,
+    upstreams: HashMap<String, Arc<Upstream>>,
+    local: LocalRecords,
+    zones: Zones,
+    fake_ip: Option<Arc<FakeIp>>,
+    observed: Option<ObservedTable>,
+    nftset: Option<NftSetWriter>,
+    chinadns: Option<ChinaDns>,
+    filter: Option<ResponseFilter>,
+    ttl: TtlConfig,
+    query_log: Option<QueryLog>,
+    dnstap: Option<Dnstap>,
+    metrics: Metrics,
+    top: Option<TopStats>,
+}
+
+impl Resolver {
+    pub async fn new(config: &Config) -> Result<Self> {
+        config.validate()?;
+        let router = Router::try_from(config)?;
+        let local = LocalRecords::try_from(&config.local)?;
+        info!(names = local.len(), "local records");
+        let zones = Zones::try_from(config.zones.as_slice())?;
+        for zone in zones.iter() {
+            info!(zone = %zone.origin(), "zone loaded");
+        }
+
+        let dnstap = config.dnstap.as_ref().map(Dnstap::try_from).transpose()?;
+        let metrics = Metrics::default();
+        metrics.set_rule_entries(rule_entries(&router));
+
+        let mut upstreams = HashMap::with_capacity(config.upstreams.len());
+        for (name, upstream) in &config.upstreams {
+            let upstream = Upstream::new(name, upstream, dnstap.as_ref(), &metrics).await?;
+            upstreams.insert(name.clone(), Arc::new(upstream));
+        }
+
+        let fake_ip = config.fake_ip.as_ref().map(|c| Arc::new(FakeIp::from(c)));
+        if let Some(fake_ip) = &fake_ip {
+            info!(restored = fake_ip.load()?, "fake ip");
+        }
+
+        let nftset = match config.rules.iter().any(|r| !r.nftset.is_empty()) {
+            true => Some(NftSetWriter::try_from(&config.nftset)?),
+            false => None,
+        };
+
+        let chinadns = match &config.chinadns {
+            Some(chinadns) => {
+                let chinadns = ChinaDns::try_from(chinadns)?;
+                info!(cidr = chinadns.len(), "chinadns");
+                Some(chinadns)
+            }
+            None => None,
+        };
+
+        let filter = match config.filter.is_empty() {
+            true => None,
+            false => {
+                let filter = ResponseFilter::try_from(&config.filter)?;
+                info!(entries = filter.len(), "response filter");
+                Some(filter)
+            }
+        };
+
+        let resolver = Self {
+            config: RwLock::new(config.clone()),
+            started: Instant::now(),
+            router: RwLock::new(Arc::new(router)),
+            temporary: TemporaryRules::default(),
+            pause: BlockPause::default(),
+            upstreams,
+            local,
+            zones,
+            fake_ip,
+            observed: config.reverse.as_ref().map(ObservedTable::from),
+            nftset,
+            chinadns,
+            filter,
+            ttl: config.ttl,
+            query_log: config
+                .query_log
+                .as_ref()
+                .map(QueryLog::try_from)
+                .transpose()?,
+            dnstap,
+            metrics,
+            top: config.top.as_ref().map(TopStats::from),
+        };
+        if let Some(dir) = &config.state_dir {
+            info!(restored = state::load(dir, &resolver).await?, "cache");
+        }
+        Ok(resolver)
+    }
+
+    /// 写完查询日志与 dnstap，保存虚假地址表、缓存与统计
+    pub async fn shutdown(&self) -> Result<()> {
+        if let Some(query_log) = &self.query_log {
+            query_log.close().await;
+        }
+        if let Some(dnstap) = &self.dnstap {
+            dnstap.close().await;
+        }
+
+        let mut result = Ok(());
+        if let Some(fake_ip) = &self.fake_ip {
+            match fake_ip.save() {
+                Ok(saved) => info!(saved, "fake ip"),
+                Err(e) => {
+                    error!(error = ?e, "fake ip save");
+                    result = Err(e);
+                }
+            }
+        }
+        if let Some(dir) = self.config().state_dir {
+            match state::save(&dir, self).await {
+                Ok(saved) => info!(saved, dir = ?dir, "cache"),
+                Err(e) => {
+                    error!(dir = ?dir, error = ?e, "state save");
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    pub fn fake_ip(&self) -> Option<&Arc<FakeIp>> {
+        self.fake_ip.as_ref()
+    }
+
+    pub fn dnstap(&self) -> Option<&Dnstap> {
+        self.dnstap.as_ref()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn top(&self) -> Option<&TopStats> {
+        self.top.as_ref()
+    }
+
+    pub fn config(&self) -> Config {
+        self.config.read().expect("[E] config lock").clone()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn upstreams(&self) -> &HashMap<String, Arc<Upstream>> {
+        &self.upstreams
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        self.router.read().expect("[E] router lock").clone()
+    }
+
+    pub fn temporary(&self) -> &TemporaryRules {
+        &self.temporary
+    }
+
+    pub fn pause(&self) -> &BlockPause {
+        &self.pause
+    }
+
+    /// 临时规则先于配置中的规则匹配，`paused` 时跳过拦截规则
+    fn route<'a, T: AsRef<[u8]>>(
+        &self,
+        router: &'a Router,
+        domain: &[T],
+        paused: bool,
+    ) -> Route<'a> {
+        let enabled = |rule: &Rule| !(paused && rule.blocks());
+        self.temporary
+            .route(domain, enabled)
+            .unwrap_or_else(|| router.route_with(domain, enabled))
+    }
+
+    /// 重新读取规则文件，出错时保留原路由表
+    pub async fn reload_rules(&self) -> Result<usize> {
+        let config = self.config();
+        let router = spawn_blocking(move || Router::try_from(&config))
+            .await
+            .map_err(Error::other)??;
+        let rules = router.rules().len();
+        self.metrics.set_rule_entries(rule_entries(&router));
+        *self.router.write().expect("[E] router lock") = Arc::new(router);
+        info!(rules, "rules reloaded");
+        Ok(rules)
+    }
+
+    /// 按完整配置校验后加入临时规则
+    pub fn add_temporary(&self, rule: RuleConfig, ttl: Duration) -> Result<()> {
+        if !rule.nftset.is_empty() && self.nftset.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "nftset needs a configured rule using nftset at startup",
+            ));
+        }
+        let mut config = self.config();
+        config.rules.push(rule.clone());
+        config.validate()?;
+
+        self.temporary.add(Rule::try_from(&rule)?, ttl)?;
+        info!(rule = %rule.name, ttl_secs = ttl.as_secs(), "temporary rule added");
+        Ok(())
+    }
+
+    /// 替换上游组的服务器
+    pub async fn set_upstream_servers(&self, name: &str, servers: Vec<String>) -> Result<()> {
+        let Some(upstream) = self.upstreams.get(name) else {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("unknown upstream group {name:?}"),
+            ));
+        };
+        if servers.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no servers"));
+        }
+        for server in &servers {
+            server.parse::<SocketAddr>().map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("server {server:?}: {e}"))
+            })?;
+        }
+
+        upstream.set_servers(&servers, &self.metrics).await?;
+        if let Some(config) = self
+            .config
+            .write()
+            .expect("[E] config lock")
+            .upstreams
+            .get_mut(name)
+        {
+            config.servers = servers;
+        }
+        Ok(())
+    }
+
+    /// 每个查询一个 span，内部日志带上客户端、域名、类型、规则与上游
+    pub async fn resolve(&self, payload: Payload, addr: SocketAddr) -> Payload {
+        let span = info_span!(
+            "query",
+            client = %addr,
+            qname = Empty,
+            qtype = Empty,
+            rule = Empty,
+            upstream = Empty,
+        );
+        async move {
+            let start = Instant::now();
+            let log = self.query_log.as_ref().filter(|l| l.accepts(addr.ip()));
+            let qtype = payload.qtype();
+            let qname = (log.is_some() || self.top.is_some()).then(|| qname(&payload.domain().0));
+            let ((response, rule), trace) = TRACE
+                .scope(RefCell::default(), async {
+                    let resolved = self.resolve_depth(payload, addr, 0).await;
+                    (resolved, TRACE.with(|t| t.take()))
+                })
+                .await;
+            let ttl = rule.as_ref().map_or(self.ttl, |r| r.ttl.or(self.ttl));
+            let response = clamp_ttl(response, ttl);
+
+            let latency = start.elapsed();
+            debug!(
+                rcode = response.rcode(),
+                latency_ms = latency.as_millis() as u64,
+                "answered"
+            );
+            self.metrics.queries.inc(match trace.rule.as_str() {
+                "" => DEFAULT_RULE,
+                rule => rule,
+            });
+            self.metrics.qtypes.inc(&rtype::name(qtype));
+            self.metrics.rcodes.inc(&rcode::name(response.rcode()));
+            self.metrics.duration.observe(latency);
+            if let Some((top, qname)) = self.top.as_ref().zip(qname.as_ref()) {
+                let blocked = rule.as_ref().is_some_and(|r| r.blocks());
+                top.record(qname, &addr.ip().to_string(), blocked);
+            }
+            if let Some((log, qname)) = log.zip(qname) {
+                log.record(QueryLogEntry {
+                    time: SystemTime::now(),
+                    client: addr,
+                    qname,
+                    qtype,
+                    rule: trace.rule,
+                    upstream: trace.upstream,
+                    response: response.clone(),
+                    cached: trace.cached,
+                    latency,
+                });
+            }
+            response
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// rewrite 递归解析目标，需要装箱
+    fn resolve_boxed(
+        &self,
+        payload: Payload,
+        addr: SocketAddr,
+        depth: usize,
+    ) -> Pin<Box<dyn Future<Output = Payload> + Send + '_>> {
+        Box::pin(async move { self.resolve_depth(payload, addr, depth).await.0 })
+    }
+
+    /// 应答及命中的规则，规则决定 TTL 上下限
+    async fn resolve_depth(
+        &self,
+        payload: Payload,
+        addr: SocketAddr,
+        depth: usize,
+    ) -> (Payload, Option<Arc<Rule>>) {
+        // 规则按小写匹配，查询可能带 0x20 随机大小写
+        let domain: Vec<Vec<u8>> = payload
+            .domain()
+            .0
+            .iter()
+            .map(|label| label.to_ascii_lowercase())
+            .collect();
+
+        let name = qname(&domain);
+        // rewrite 的目标不覆盖原查询的字段
+        let span = Span::current();
+        if depth == 0 {
+            span.record("qname", name.as_str());
+            span.record("qtype", rtype::name(payload.qtype()).as_str());
+        }
+
+        // 本地记录优先于路由规则
+        if self.local.contains(&name) {
+            trace_rule(depth, "local");
+            debug!("local");
+            let response = synthesize(payload, |query| {
+                self.local
+                    .answer(query)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "query without question"))
+            });
+            return (response, None);
+        }
+
+        if let Some(zone) = self.zones.find(&name) {
+            trace_rule(depth, "zone");
+            debug!(zone = %zone.origin(), "zone");
+            return (synthesize(payload, |query| zone.answer(query)), None);
+        }
+
+        if let Some(fake_ip) = self
+            .fake_ip
+            .as_ref()
+            .filter(|_| payload.qtype() == rtype::PTR)
+        {
+            if let Some(reply) = Message::try_from(&payload)
+                .ok()
+                .and_then(|query| fake_ip.answer_ptr(&query))
+            {
+                trace_rule(depth, "fake-ip");
+                return (synthesize(payload, |_| Ok(reply)), None);
+            }
+        }
+
+        let router = self.router();
+        let paused = self.pause.is_paused(addr.ip());
+        let rule = match self.route(&router, &domain, paused) {
+            Route::Rule(rule, matched) => {
+                trace_rule(depth, &rule.name);
+                debug!(%matched, "rule matched");
+                rule
+            }
+            Route::Default(group) => {
+                trace_rule(depth, DEFAULT_RULE);
+                let response = match &self.chinadns {
+                    Some(chinadns) => self.forward_chinadns(chinadns, payload, &name).await,
+                    None => self.forward(group, payload, &name, None).await,
+                };
+                return (response, None);
+            }
+        };
+
+        if rule.nodata.contains(&payload.qtype()) {
+            debug!("nodata");
+            let response = synthesize(payload, |query| Ok(Message::reply(query)));
+            return (response, Some(rule));
+        }
+
+        let response = match &rule.action {
+            Action::Forward(group) => self.forward(group, payload, &name, Some(&rule)).await,
+            Action::Block(mode) => synthesize(payload, |query| block_response(query, *mode)),
+            Action::Static(records) => synthesize(payload, |query| static_response(query, records)),
+            Action::FakeIp(group) => {
+                // 配置校验保证使用 fake_ip 时地址池存在
+                match self.fake_ip.as_ref().and_then(|fake_ip| {
+                    Message::try_from(&payload)
+                        .ok()
+                        .and_then(|query| fake_ip.answer(&query))
+                }) {
+                    Some(reply) => synthesize(payload, |_| Ok(reply)),
+                    None => self.forward(group, payload, &name, Some(&rule)).await,
+                }
+            }
+            Action::Rewrite(rewrite) => self.rewrite(payload, addr, rewrite, depth).await,
+            Action::Override(action) => {
+                let qtype = payload.qtype();
+                let response = self
+                    .forward(&action.forward, payload, &name, Some(&rule))
+                    .await;
+                override_ips(response, qtype, &action.ips)
+            }
+        };
+        (response, Some(rule))
+    }
+
+    /// 以相同类型解析目标域名，结果改写为对原域名的应答
+    async fn rewrite(
+        &self,
+        mut payload: Payload,
+        addr: SocketAddr,
+        rewrite: &RewriteAction,
+        depth: usize,
+    ) -> Payload {
+        let query = match Message::try_from(&payload) {
+            Ok(query) if query.question().is_none() => {
+                error!(target = %rewrite.target, "rewrite query without question");
+                payload.servfail();
+                return payload;
+            }
+            Ok(query) if depth < MAX_REWRITE_DEPTH => query,
+            Ok(_) => {
+                warn!(target = %rewrite.target, depth, "rewrite too deep");
+                payload.servfail();
+                return payload;
+            }
+            Err(e) => {
+                error!(error = ?e, "rewrite parse");
+                payload.servfail();
+                return payload;
+            }
+        };
+
+        let target = rewrite.target.trim_end_matches('.').to_ascii_lowercase();
+        let mut target_query = query.clone();
+        target_query.questions[0].name = target.clone();
+        let target_query = match Payload::try_from(&target_query) {
+            Ok(target_query) => target_query,
+            Err(e) => {
+                error!(%target, error = ?e, "rewrite target");
+                payload.servfail();
+                return payload;
+            }
+        };
+        let response = self.resolve_boxed(target_query, addr, depth + 1).await;
+
+        match Message::try_from(&response).and_then(|response| {
+            Payload::try_from(&rewrite_reply(&query, &response, &target, rewrite.flatten))
+        }) {
+            Ok(reply) => reply,
+            Err(e) => {
+                error!(%target, error = ?e, "rewrite response");
+                payload.servfail();
+                payload
+            }
+        }
+    }
+
+    async fn forward(
+        &self,
+        group: &str,
+        payload: Payload,
+        name: &str,
+        rule: Option<&Rule>,
+    ) -> Payload {
+        // 分组名已在配置校验时确认存在
+        let reply = self.upstreams[group].query(payload).await;
+        trace_upstream(group, reply.cached);
+        let mut response = self.filter(reply.payload);
+        if let Some(rule) = rule.filter(|r| !r.strip.is_empty()) {
+            response = strip_types(response, &rule.strip);
+        }
+        self.observe(&response, name, rule);
+        response
+    }
+
+    /// 国外查询在后台进行，国内应答可信时直接返回而不等待
+    async fn forward_chinadns(&self, chinadns: &ChinaDns, payload: Payload, name: &str) -> Payload {
+        let foreign = self.upstreams[&chinadns.foreign].clone();
+        let foreign = spawn(
+            {
+                let payload = payload.clone();
+                async move { foreign.query(payload).await }
+            }
+            .in_current_span(),
+        );
+
+        let mut reply = self.upstreams[&chinadns.domestic].query(payload).await;
+        let mut group = &chinadns.domestic;
+        if !chinadns.trusts(&reply.payload) {
+            debug!("chinadns domestic answer not trusted");
+            match foreign.await {
+                Ok(foreign) => {
+                    reply = foreign;
+                    group = &chinadns.foreign;
+                }
+                Err(e) => error!(error = ?e, "chinadns foreign query"),
+            }
+        }
+        trace_upstream(group, reply.cached);
+        let response = self.filter(reply.payload);
+        self.observe(&response, name, None);
+        response
+    }
+
+    fn filter(&self, response: Payload) -> Payload {
+        match &self.filter {
+            Some(filter) => filter.apply(response),
+            None => response,
+        }
+    }
+
+    fn observe(&self, response: &Payload, name: &str, rule: Option<&Rule>) {
+        let nftset = rule
+            .map(|r| r.nftset.as_slice())
+            .filter(|sets| !sets.is_empty())
+            .zip(self.nftset.as_ref());
+        if self.observed.is_none() && nftset.is_none() {
+            return;
+        }
+
+        // 应答中的地址供反查，或写入规则配置的 nftables 集合
+        if let Ok(message) = Message::try_from(response) {
+            if let Some(observed) = &self.observed {
+                let rule = rule.map_or(DEFAULT_RULE, |r| r.name.as_str());
+                observed.record(name, rule, message.answer_ips());
+            }
+            if let Some((sets, writer)) = nftset {
+                writer.add(sets, message.answer_ips());
+            }
+        }
+    }
+
+    /// 地址对应的域名与规则，虚假地址的规则按当前路由表重新匹配
+    pub fn reverse_lookup(&self, ip: IpAddr) -> Vec<Entry> {
+        let mut entries = Vec::new();
+
+        if let Some(mapping) = self.fake_ip.as_ref().and_then(|f| f.lookup(ip)) {
+            let reversed: Vec<&str> = mapping.domain.split('.').rev().collect();
+            let rule = match self.route(&self.router(), &reversed, false) {
+                Route::Rule(rule, _) => rule.name.clone(),
+                Route::Default(_) => DEFAULT_RULE.into(),
+            };
+            entries.push(Entry {
+                rule,
+                source: "fake-ip",
+                ttl: mapping.ttl(),
+                domain: mapping.domain,
+            });
+        }
+
+        if let Some(observed) = &self.observed {
+            entries.extend(observed.lookup(ip));
+        }
+        entries
+    }
+}
+
+/// (规则名, 域名条数)，用于指标
+fn rule_entries(router: &Router) -> Vec<(String, usize)> {
+    router
+        .rules()
+        .iter()
+        .map(|rule| (rule.name.clone(), rule.trie.len()))
+        .collect()
+}
+
+/// 倒序标签还原为小写的点分域名
+fn qname<T: AsRef<[u8]>>(reversed_domain: &[T]) -> String {
+    let labels: Vec<_> = reversed_domain
+        .iter()
+        .rev()
+        .map(|v| String::from_utf8_lossy(v.as_ref()).to_ascii_lowercase())
+        .collect();
+    labels.join(".")
+}
+
+/// 根据请求构造本地应答，请求无法解析时返回 SERVFAIL
+fn synthesize<F>(mut payload: Payload, build: F) -> Payload
+where
+    F: FnOnce(&Message) -> Result<Message>,
+{
+    match Message::try_from(&payload)
+        .and_then(|query| build(&query))
+        .and_then(|reply| Payload::try_from(&reply))
+    {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!(error = ?e, "synthesize response");
+            payload.servfail();
+            payload
+        }
+    }
+}
+
+/// 目标的应答改写为原域名的应答：CNAME 指向目标，或展平为目标的同类型记录
+fn rewrite_reply(query: &Message, response: &Message, target: &str, flatten: bool) -> Message {
+    let mut reply = Message::reply(query);
+    reply.set_rcode(response.rcode());
+    reply.authorities = response.authorities.clone();
+    let Some(question) = query.question() else {
+        return reply;
+    };
+    // 目标解析失败时不带半截的 CNAME 链
+    if response.rcode() == rcode::SERVFAIL {
+        return reply;
+    }
+
+    if flatten {
+        reply.answers = response
+            .answers
+            .iter()
+            .filter(|r| r.rtype == question.qtype || question.qtype == rtype::ANY)
+            .filter(|r| r.rtype != rtype::CNAME)
+            .map(|r| Record {
+                name: question.name.clone(),
+                ..r.clone()
+            })
+            .collect();
+    } else {
+        reply.answers.push(Record::with_type(
+            &question.name,
+            rtype::CNAME,
+            REWRITE_TTL,
+            RData::Name(target.into()),
+        ));
+        reply.answers.extend(response.answers.iter().cloned());
+    }
+    reply
+}
+
+/// 替换应答中与查询类型相同的地址记录；应答没有地址时补在 CNAME 链末端
+fn override_ips(response: Payload, qtype: u16, ips: &[IpAddr]) -> Payload {
+    let data: Vec<RData> = ips
+        .iter()
+        .filter_map(|ip| match (qtype, ip) {
+            (rtype::A, IpAddr::V4(ip)) => Some(RData::A(*ip)),
+            (rtype::AAAA, IpAddr::V6(ip)) => Some(RData::Aaaa(*ip)),
+            _ => None,
+        })
+        .collect();
+    if data.is_empty() {
+        return response;
+    }
+    let Ok(mut message) = Message::try_from(&response) else {
+        return response;
+    };
+    let Some(question) = message.question().cloned() else {
+        return response;
+    };
+
+    let (name, ttl) = match message.answers.iter().find(|r| r.rtype == qtype) {
+        Some(r) => (r.name.clone(), r.ttl),
+        None => {
+            let name = match message
+                .answers
+                .iter()
+                .rev()
+                .find(|r| r.rtype == rtype::CNAME)
+            {
+                Some(Record {
+                    data: RData::Name(target),
+                    ..
+                }) => target.clone(),
+                _ => question.name.clone(),
+            };
+            (name, REWRITE_TTL)
+        }
+    };
+    message.answers.retain(|r| r.rtype != qtype);
+    for data in data {
+        message
+            .answers
+            .push(Record::with_type(&name, qtype, ttl, data));
+    }
+    message.set_rcode(0);
+    Payload::try_from(&message).unwrap_or(response)
+}
+
+/// 原地改写各记录的 TTL 字段
+fn clamp_ttl(mut response: Payload, ttl: TtlConfig) -> Payload {
+    if ttl.is_empty() {
+        return response;
+    }
+    let offsets = match ttl_offsets(response.as_ref()) {
+        Ok(offsets) => offsets,
+        Err(e) => {
+            error!(error = ?e, "clamp ttl");
+            return response;
+        }
+    };
+    for offset in offsets {
+        let field = &mut response.0[offset..offset + 4];
+        let value = u32::from_be_bytes([field[0], field[1], field[2], field[3]]);
+        field.copy_from_slice(&ttl.clamp(value).to_be_bytes());
+    }
+    response
+}
+
+/// 删除应答与附加段中指定类型的记录，未改动时原样返回
+fn strip_types(response: Payload, types: &[u16]) -> Payload {
+    let Ok(mut message) = Message::try_from(&response) else {
+        return response;
+    };
+    let count = message.answers.len() + message.additionals.len();
+    message.answers.retain(|r| !types.contains(&r.rtype));
+    message.additionals.retain(|r| !types.contains(&r.rtype));
+    if message.answers.len() + message.additionals.len() == count {
+        return response;
+    }
+    Payload::try_from(&message).unwrap_or(response)
+}
+
+fn question(query: &Message) -> Result<(&str, u16)> {
+    query
+        .question()
+        .map(|q| (q.name.as_str(), q.qtype))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "query without question"))
+}
+
+pub fn block_response(query: &Message, mode: BlockMode) -> Result<Message> {
+    let (name, qtype) = question(query)?;
+    let mut reply = Message::reply(query);
+
+    match mode {
+        BlockMode::ZeroIp => match qtype {
+            rtype::A => reply.answers.push(Record::new(
+                name,
+                BLOCK_TTL,
+                RData::A(Ipv4Addr::UNSPECIFIED),
+            )),
+            rtype::AAAA => reply.answers.push(Record::new(
+                name,
+                BLOCK_TTL,
+                RData::Aaaa(Ipv6Addr::UNSPECIFIED),
+            )),
+            _ => {}
+        },
+        BlockMode::Nxdomain => reply.set_rcode(rcode::NXDOMAIN),
+        BlockMode::Nodata => {}
+        BlockMode::Refused => reply.set_rcode(rcode::REFUSED),
+    }
+
+    Ok(reply)
+}
+
+fn static_response(query: &Message, records: &[StaticRecord]) -> Result<Message> {
+    let (name, qtype) = question(query)?;
+    let mut reply = Message::reply(query);
+
+    for record in records {
+        let (rtype, data) = record.parse()?;
+        if rtype == qtype || rtype == rtype::CNAME || qtype == rtype::ANY {
+            reply
+                .answers
+                .push(Record::with_type(name, rtype, record.ttl, data));
+        }
+    }
+
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_rewrite_override() {
+        let a = |s: &str| RData::A(s.parse().unwrap());
+        let mut target = Message::reply(&Message::query("npm.cache.internal", rtype::A));
+        target
+            .answers
+            .push(Record::new("npm.cache.internal", 30, a("10.1.1.1")));
+
+        let orig = Message::query("registry.npmjs.org", rtype::A);
+        let reply = rewrite_reply(&orig, &target, "npm.cache.internal", false);
+        assert_eq!(reply.id, 7);
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[0].rtype, rtype::CNAME);
+        assert_eq!(reply.answers[1].name, "npm.cache.internal");
+
+        let reply = rewrite_reply(&orig, &target, "npm.cache.internal", true);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].name, "registry.npmjs.org");
+        assert_eq!(reply.answers[0].ttl, 30);
+
+        let ips: Vec<IpAddr> = vec!["10.0.0.5".parse().unwrap(), "fd00::5".parse().unwrap()];
+        let mut upstream = Message::reply(&orig);
+        upstream.answers.push(Record::with_type(
+            "registry.npmjs.org",
+            rtype::CNAME,
+            300,
+            RData::Name("edge.npmjs.org".into()),
+        ));
+        upstream
+            .answers
+            .push(Record::new("edge.npmjs.org", 120, a("1.2.3.4")));
+        upstream
+            .answers
+            .push(Record::new("edge.npmjs.org", 120, a("1.2.3.5")));
+        let response = override_ips(Payload::try_from(&upstream).unwrap(), rtype::A, &ips);
+        let message = Message::try_from(&response).unwrap();
+        assert_eq!(message.answers.len(), 2);
+        assert_eq!(message.answers[1].data, a("10.0.0.5"));
+        assert_eq!(message.answers[1].name, "edge.npmjs.org");
+        assert_eq!(message.answers[1].ttl, 120);
+
+        // 上游 NXDOMAIN 时在问题名上补地址
+        let mut upstream = Message::reply(&Message::query("api.example.com", rtype::AAAA));
+        upstream.set_rcode(rcode::NXDOMAIN);
+        let response = override_ips(Payload::try_from(&upstream).unwrap(), rtype::AAAA, &ips);
+        let message = Message::try_from(&response).unwrap();
+        assert_eq!(message.rcode(), 0);
+        assert_eq!(message.answers[0].name, "api.example.com");
+        assert_eq!(
+            message.answers[0].data,
+            RData::Aaaa("fd00::5".parse().unwrap())
+        );
+
+        // 其它类型不受影响
+        let response = override_ips(Payload::try_from(&upstream).unwrap(), rtype::TXT, &ips);
+        assert_eq!(
+            Message::try_from(&response).unwrap().rcode(),
+            rcode::NXDOMAIN
+        );
+    }
+
+    #[test]
+    fn it_work_clamp_ttl() {
+        let mut q = Message::query("cdn.example.com", rtype::A);
+        q.additionals
+            .push(Record::with_type("", rtype::OPT, 0, RData::Raw(Vec::new())));
+        let mut reply = Message::reply(&q);
+        reply.answers.push(Record::new(
+            "cdn.example.com",
+            0,
+            RData::A([1, 2, 3, 4].into()),
+        ));
+        reply.answers.push(Record::new(
+            "cdn.example.com",
+            86400,
+            RData::A([1, 2, 3, 5].into()),
+        ));
+
+        let ttl = TtlConfig {
+            min: Some(30),
+            max: Some(3600),
+        };
+        let message =
+            Message::try_from(&clamp_ttl(Payload::try_from(&reply).unwrap(), ttl)).unwrap();
+        let ttls: Vec<u32> = message.answers.iter().map(|r| r.ttl).collect();
+        assert_eq!(ttls, [30, 3600]);
+        // OPT 的 TTL 字段不是 TTL
+        assert_eq!(message.edns().unwrap().ttl, 0);
+    }
+}
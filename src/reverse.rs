@@ -0,0 +1,217 @@
+//! 反查接口：透明代理按目标地址查询其来源域名与命中的规则
+//!
+//! Unix socket 上的行协议，每行一个地址，应答若干行后以空行结束：
+//!
+//! ```text
+//! > 198.18.0.1
+//! < www.google.com proxy fake-ip 3597
+//! <
+//! > 1.2.3.4
+//! < example.com direct resolved 582
+//! < example.net direct resolved 31
+//! <
+//! > x
+//! < ERR invalid ip "x"
+//! <
+//! ```
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Result,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    spawn,
+};
+use tracing::{error, info};
+
+use crate::{config::ReverseConfig, resolver::Resolver};
+
+/// 同一地址最多记录的域名数（CDN 地址常被多个域名共用）
+const MAX_DOMAINS_PER_IP: usize = 8;
+
+pub struct Entry {
+    pub domain: String,
+    pub rule: String,
+    pub source: &'static str,
+    /// 剩余有效秒数
+    pub ttl: u64,
+}
+
+struct Observed {
+    domain: String,
+    rule: String,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct Table {
+    by_ip: HashMap<IpAddr, Vec<Observed>>,
+    /// 地址首次记录的顺序，超出容量时从最早的开始淘汰
+    order: VecDeque<IpAddr>,
+}
+
+/// 转发应答中出现过的 A/AAAA 地址
+pub struct ObservedTable {
+    capacity: usize,
+    min_ttl: u64,
+    table: Mutex<Table>,
+}
+
+impl From<&ReverseConfig> for ObservedTable {
+    fn from(config: &ReverseConfig) -> Self {
+        Self {
+            capacity: config.capacity,
+            min_ttl: config.ttl,
+            table: Mutex::new(Table::default()),
+        }
+    }
+}
+
+impl ObservedTable {
+    /// 记录一次解析结果，保留时间至少为 `min_ttl`，代理通常在记录过期后仍会连接
+    pub fn record<I>(&self, domain: &str, rule: &str, ips: I)
+    where
+        I: IntoIterator<Item = (IpAddr, u32)>,
+    {
+        let now = Instant::now();
+        let mut table = self.table.lock().expect("[E] reverse lock");
+
+        for (ip, ttl) in ips {
+            let expires_at = now + Duration::from_secs(self.min_ttl.max(ttl as u64));
+            let entries = table.by_ip.entry(ip).or_default();
+            let is_new = entries.is_empty();
+
+            entries.retain(|e| e.expires_at > now);
+            match entries.iter_mut().find(|e| e.domain == domain) {
+                Some(entry) => {
+                    entry.rule = rule.into();
+                    entry.expires_at = expires_at;
+                }
+                None => {
+                    if entries.len() >= MAX_DOMAINS_PER_IP {
+                        entries.remove(0);
+                    }
+                    entries.push(Observed {
+                        domain: domain.into(),
+                        rule: rule.into(),
+                        expires_at,
+                    });
+                }
+            }
+
+            if is_new {
+                table.order.push_back(ip);
+            }
+        }
+
+        while table.by_ip.len() > self.capacity {
+            let Some(ip) = table.order.pop_front() else {
+                break;
+            };
+            table.by_ip.remove(&ip);
+        }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Vec<Entry> {
+        let now = Instant::now();
+        let table = self.table.lock().expect("[E] reverse lock");
+        table
+            .by_ip
+            .get(&ip)
+            .into_iter()
+            .flatten()
+            .filter(|e| e.expires_at > now)
+            .map(|e| Entry {
+                domain: e.domain.clone(),
+                rule: e.rule.clone(),
+                source: "resolved",
+                ttl: (e.expires_at - now).as_secs(),
+            })
+            .collect()
+    }
+}
+
+async fn handle(stream: UnixStream, resolver: Arc<Resolver>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut response = String::new();
+        match line.parse::<IpAddr>() {
+            Ok(ip) => {
+                for e in resolver.reverse_lookup(ip) {
+                    response.push_str(&format!("{} {} {} {}\n", e.domain, e.rule, e.source, e.ttl));
+                }
+            }
+            Err(_) => response.push_str(&format!("ERR invalid ip {line:?}\n")),
+        }
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// 监听反查 socket，启动前清理上次遗留的 socket 文件
+pub async fn serve(path: &Path, resolver: Arc<Resolver>) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!(socket = ?path, "reverse lookup");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let resolver = resolver.clone();
+        spawn(async move {
+            if let Err(e) = handle(stream, resolver).await {
+                error!(error = ?e, "reverse lookup");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_observed() {
+        let table = ObservedTable::from(&ReverseConfig {
+            socket: "/tmp/fakedns.sock".into(),
+            capacity: 2,
+            ttl: 600,
+        });
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        table.record("a.com", "direct", [(ip("1.1.1.1"), 60)]);
+        table.record("b.com", "proxy", [(ip("1.1.1.1"), 3600)]);
+        table.record("a.com", "default", [(ip("1.1.1.1"), 60)]);
+
+        let entries = table.lookup(ip("1.1.1.1"));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].domain, "a.com");
+        assert_eq!(entries[0].rule, "default");
+        assert!(entries[0].ttl >= 599);
+        assert!(entries[1].ttl >= 3599);
+
+        // 超出容量时淘汰最早记录的地址
+        table.record(
+            "c.com",
+            "direct",
+            [(ip("2.2.2.2"), 60), (ip("3.3.3.3"), 60)],
+        );
+        assert!(table.lookup(ip("1.1.1.1")).is_empty());
+        assert_eq!(table.lookup(ip("3.3.3.3"))[0].domain, "c.com");
+    }
+}
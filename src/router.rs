@@ -0,0 +1,228 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Action, Config, RuleConfig, TtlConfig},
+    nftset::NftSet,
+    trie::{DomainMatch, DomainTrie, Trie},
+};
+
+pub struct Rule {
+    pub name: String,
+    pub trie: DomainTrie,
+    pub action: Action,
+    pub nftset: Vec<Arc<NftSet>>,
+    /// 直接应答 NODATA 的查询类型
+    pub nodata: Vec<u16>,
+    /// 从转发应答中删除的记录类型
+    pub strip: Vec<u16>,
+    /// 未设置的一端沿用全局
+    pub ttl: TtlConfig,
+}
+
+impl Rule {
+    pub fn blocks(&self) -> bool {
+        matches!(self.action, Action::Block(_))
+    }
+}
+
+impl TryFrom<&RuleConfig> for Rule {
+    type Error = Error;
+
+    fn try_from(config: &RuleConfig) -> Result<Self> {
+        let mut trie: DomainTrie = Trie::with_capacity(config.domains.len()).into();
+        if let Some(file) = &config.file {
+            trie.extend_file(file).map_err(|e| {
+                Error::new(e.kind(), format!("rule {:?} {file:?}: {e}", config.name))
+            })?;
+        }
+        if !config.domains.is_empty() {
+            trie.extend_content("inline", &config.domains.join("\n"));
+        }
+        trie.shrink_to_fit();
+
+        Ok(Self {
+            name: config.name.clone(),
+            trie,
+            action: config.action.clone(),
+            nftset: config.nftset.iter().cloned().map(Arc::new).collect(),
+            nodata: config.nodata_types()?,
+            strip: config.strip_types()?,
+            ttl: config.ttl,
+        })
+    }
+}
+
+pub enum Route<'a> {
+    Rule(Arc<Rule>, DomainMatch),
+    Default(&'a str),
+}
+
+pub struct Router {
+    rules: Vec<Arc<Rule>>,
+    default: String,
+}
+
+impl TryFrom<&Config> for Router {
+    type Error = Error;
+
+    fn try_from(config: &Config) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| Rule::try_from(rule).map(Arc::new))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            rules,
+            default: config.default.clone(),
+        })
+    }
+}
+
+impl Router {
+    pub fn rules(&self) -> &[Arc<Rule>] {
+        &self.rules
+    }
+
+    /// 按规则顺序匹配倒序的域名标签
+    pub fn route<T: AsRef<[u8]>>(&self, reversed_domain: &[T]) -> Route<'_> {
+        self.route_with(reversed_domain, |_| true)
+    }
+
+    /// 只匹配 `enabled` 为真的规则，其余规则视为不存在
+    pub fn route_with<T: AsRef<[u8]>>(
+        &self,
+        reversed_domain: &[T],
+        enabled: impl Fn(&Rule) -> bool,
+    ) -> Route<'_> {
+        self.rules
+            .iter()
+            .filter(|rule| enabled(rule))
+            .find_map(|rule| {
+                rule.trie
+                    .domain_match(reversed_domain)
+                    .map(|m| Route::Rule(rule.clone(), m))
+            })
+            .unwrap_or(Route::Default(&self.default))
+    }
+
+    /// 每条规则各自的匹配结果，用于排查被前面规则遮蔽的匹配
+    pub fn explain<'a, T: AsRef<[u8]>>(
+        &'a self,
+        reversed_domain: &'a [T],
+    ) -> impl Iterator<Item = (&'a Rule, Option<DomainMatch>)> + 'a {
+        self.rules
+            .iter()
+            .map(move |rule| (&**rule, rule.trie.domain_match(reversed_domain)))
+    }
+}
+
+/// 运行时添加的临时规则，先于配置中的规则匹配，到期后不再生效
+#[derive(Default)]
+pub struct TemporaryRules(RwLock<Vec<(Arc<Rule>, Instant)>>);
+
+impl TemporaryRules {
+    /// 同名的临时规则被替换，有效期过长时返回 `InvalidInput`
+    pub fn add(&self, rule: Rule, ttl: Duration) -> Result<()> {
+        let now = Instant::now();
+        let expires = now
+            .checked_add(ttl)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "expire too large"))?;
+        let mut rules = self.0.write().expect("[E] temporary rules lock");
+        rules.retain(|(r, expires)| r.name != rule.name && *expires > now);
+        rules.push((Arc::new(rule), expires));
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        let mut rules = self.0.write().expect("[E] temporary rules lock");
+        let len = rules.len();
+        rules.retain(|(r, _)| r.name != name);
+        rules.len() != len
+    }
+
+    pub fn route<T: AsRef<[u8]>>(
+        &self,
+        reversed_domain: &[T],
+        enabled: impl Fn(&Rule) -> bool,
+    ) -> Option<Route<'static>> {
+        let now = Instant::now();
+        self.0
+            .read()
+            .expect("[E] temporary rules lock")
+            .iter()
+            .filter(|(rule, expires)| *expires > now && enabled(rule))
+            .find_map(|(rule, _)| {
+                rule.trie
+                    .domain_match(reversed_domain)
+                    .map(|m| Route::Rule(rule.clone(), m))
+            })
+    }
+
+    /// 未到期的规则及剩余时间
+    pub fn list(&self) -> Vec<(Arc<Rule>, Duration)> {
+        let now = Instant::now();
+        self.0
+            .read()
+            .expect("[E] temporary rules lock")
+            .iter()
+            .filter(|(_, expires)| *expires > now)
+            .map(|(rule, expires)| (rule.clone(), *expires - now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, domain: &str) -> Rule {
+        let config: RuleConfig = toml::from_str(&format!(
+            "name = {name:?}\ndomains = [{domain:?}]\naction = {{ block = \"nxdomain\" }}"
+        ))
+        .unwrap();
+        Rule::try_from(&config).unwrap()
+    }
+
+    #[test]
+    fn it_work_temporary_rules() {
+        let temporary = TemporaryRules::default();
+        let domain = ["com", "example", "ads"];
+        assert!(temporary.route(&domain, |_| true).is_none());
+
+        temporary
+            .add(rule("ads", "example.com"), Duration::from_secs(60))
+            .unwrap();
+        temporary
+            .add(rule("gone", "ads.example.com"), Duration::ZERO)
+            .unwrap();
+        // 过长的有效期被拒绝，锁不受影响
+        let e = temporary
+            .add(rule("huge", "example.com"), Duration::from_secs(u64::MAX))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(temporary.route(&domain, |r| !r.blocks()).is_none());
+        match temporary.route(&domain, |_| true) {
+            Some(Route::Rule(rule, m)) => assert_eq!(
+                (rule.name.as_str(), m.suffix.as_str()),
+                ("ads", "example.com")
+            ),
+            _ => panic!("expected temporary rule"),
+        }
+        // 同名替换，过期的不再列出
+        temporary
+            .add(rule("ads", "other.com"), Duration::from_secs(60))
+            .unwrap();
+        let list = temporary.list();
+        assert_eq!(list.len(), 1);
+        assert!(temporary.route(&domain, |_| true).is_none());
+
+        assert!(temporary.remove("ads"));
+        assert!(!temporary.remove("ads"));
+        assert!(temporary.list().is_empty());
+    }
+}
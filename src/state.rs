@@ -0,0 +1,126 @@
+//! 关闭时保存、启动时恢复的运行状态：上游缓存，以及供查看的统计快照
+use serde::Serialize;
+use std::{
+    fs::{read_to_string, rename, File},
+    io::{BufWriter, ErrorKind, Result, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{message::Message, metrics::Stats, payload::Payload, resolver::Resolver, topk::Report};
+
+/// 每行 `上游组 服务器 过期时间 应答的十六进制`
+const CACHE_FILE: &str = "cache";
+const STATS_FILE: &str = "stats.json";
+
+#[derive(Debug, Serialize)]
+struct Snapshot {
+    saved_at: u64,
+    uptime_secs: u64,
+    stats: Stats,
+    top: Option<Report>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// 先写临时文件再改名，避免中途退出留下半个文件
+fn write_atomic(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = BufWriter::new(File::create(&tmp)?);
+    write(&mut f)?;
+    f.flush()?;
+    drop(f);
+    rename(tmp, path)
+}
+
+/// 返回保存的缓存条数
+pub async fn save(dir: &Path, resolver: &Resolver) -> Result<usize> {
+    let mut lines = Vec::new();
+    for (name, upstream) in resolver.upstreams() {
+        for (server, expires_at, payload) in upstream.export_cache().await {
+            lines.push(format!(
+                "{name} {server} {expires_at} {}",
+                hex(payload.as_ref())
+            ));
+        }
+    }
+    write_atomic(&dir.join(CACHE_FILE), |f| {
+        lines.iter().try_for_each(|line| writeln!(f, "{line}"))
+    })?;
+
+    let snapshot = Snapshot {
+        saved_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        uptime_secs: resolver.uptime().as_secs(),
+        stats: resolver.metrics().stats(),
+        top: resolver.top().map(|top| top.report(usize::MAX)),
+    };
+    write_atomic(&dir.join(STATS_FILE), |f| {
+        serde_json::to_writer_pretty(&mut *f, &snapshot)?;
+        writeln!(f)
+    })?;
+    Ok(lines.len())
+}
+
+/// 恢复缓存，无法解析的行直接跳过，返回恢复的条数
+pub async fn load(dir: &Path, resolver: &Resolver) -> Result<usize> {
+    let content = match read_to_string(dir.join(CACHE_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut restored = 0;
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [name, server, expires_at, payload] = fields[..] else {
+            continue;
+        };
+        let (Ok(server), Ok(expires_at), Some(payload)) = (
+            server.parse::<SocketAddr>(),
+            expires_at.parse::<u64>(),
+            unhex(payload),
+        ) else {
+            continue;
+        };
+        let payload = Payload(payload);
+        if Message::try_from(&payload).is_err() {
+            continue;
+        }
+        if let Some(upstream) = resolver.upstreams().get(name) {
+            restored += upstream
+                .import_cache(server, vec![(expires_at, payload)])
+                .await;
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_hex() {
+        let bytes = [0x00, 0x7f, 0xab, 0xff];
+        assert_eq!(hex(&bytes), "007fabff");
+        assert_eq!(unhex("007fabff").unwrap(), bytes);
+        assert_eq!(unhex("007"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
@@ -0,0 +1,83 @@
+//! 后台任务的看护：任务出错或 panic 后按退避间隔重启，正常结束则不再重启
+use std::{
+    future::Future,
+    io::Result,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{spawn, task::JoinHandle, time::sleep};
+use tracing::error;
+
+use crate::metrics::Labeled;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// 运行超过这么久才退出的任务，下次重启从最短间隔开始
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// 看护任务被取消时一并取消正在运行的任务
+struct AbortOnDrop(JoinHandle<Result<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// `start` 每次重启时调用一次，重启次数按任务名计入 `restarts`
+pub fn supervise<F, Fut>(name: &str, restarts: Arc<Labeled>, mut start: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let name = name.to_string();
+    spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let mut task = AbortOnDrop(spawn(start()));
+            match (&mut task.0).await {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => error!(task = %name, error = ?e, "task failed"),
+                Err(e) if e.is_panic() => error!(task = %name, error = ?e, "task panicked"),
+                Err(_) => return,
+            }
+
+            restarts.inc(&name);
+            if started.elapsed() >= STABLE_AFTER {
+                backoff = MIN_BACKOFF;
+            }
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Error,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    #[tokio::test]
+    async fn it_work_supervise() {
+        let restarts = Arc::new(Labeled::default());
+        let runs = Arc::new(AtomicU32::new(0));
+        let runs_c = runs.clone();
+        let handle = supervise("flaky", restarts.clone(), move || {
+            let runs = runs_c.clone();
+            async move {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    0 => panic!("first run"),
+                    1 => Err(Error::other("second run")),
+                    _ => Ok(()),
+                }
+            }
+        });
+        handle.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(restarts.snapshot()["flaky"], 2);
+    }
+}
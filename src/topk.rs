@@ -0,0 +1,163 @@
+//! Space-Saving 近似 Top-K 计数，内存只与容量有关
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use crate::config::TopConfig;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Item {
+    pub name: String,
+    /// 估计次数，不小于真实次数
+    pub count: u64,
+    /// 估计的上界误差，真实次数不小于 count - error
+    pub error: u64,
+}
+
+/// 容量满后新元素替换计数最小者并继承其计数
+#[derive(Debug)]
+pub struct SpaceSaving {
+    capacity: usize,
+    /// 名称 → (次数, 误差)
+    counters: HashMap<String, (u64, u64)>,
+    /// (次数, 名称)，用于找出最小者与排序
+    order: BTreeSet<(u64, String)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+            order: BTreeSet::new(),
+        }
+    }
+
+    pub fn inc(&mut self, name: &str) {
+        if let Some((count, _)) = self.counters.get_mut(name) {
+            self.order.remove(&(*count, name.to_string()));
+            *count += 1;
+            self.order.insert((*count, name.into()));
+            return;
+        }
+
+        let (count, error) = match self.counters.len() < self.capacity {
+            true => (1, 0),
+            false => {
+                let Some((min, evicted)) = self.order.pop_first() else {
+                    return;
+                };
+                self.counters.remove(&evicted);
+                (min + 1, min)
+            }
+        };
+        self.counters.insert(name.into(), (count, error));
+        self.order.insert((count, name.into()));
+    }
+
+    /// 按次数从大到小取前 n 个
+    pub fn top(&self, n: usize) -> Vec<Item> {
+        self.order
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, name)| Item {
+                name: name.clone(),
+                count: *count,
+                error: self.counters[name].1,
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.counters.clear();
+        self.order.clear();
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub domains: Vec<Item>,
+    pub blocked: Vec<Item>,
+    pub clients: Vec<Item>,
+}
+
+/// 查询最多的域名、被拦截最多的域名与查询最多的客户端
+#[derive(Debug)]
+pub struct TopStats {
+    domains: Mutex<SpaceSaving>,
+    blocked: Mutex<SpaceSaving>,
+    clients: Mutex<SpaceSaving>,
+}
+
+impl From<&TopConfig> for TopStats {
+    fn from(config: &TopConfig) -> Self {
+        Self {
+            domains: Mutex::new(SpaceSaving::new(config.capacity)),
+            blocked: Mutex::new(SpaceSaving::new(config.capacity)),
+            clients: Mutex::new(SpaceSaving::new(config.capacity)),
+        }
+    }
+}
+
+impl TopStats {
+    pub fn record(&self, domain: &str, client: &str, blocked: bool) {
+        self.domains.lock().expect("[E] top lock").inc(domain);
+        if blocked {
+            self.blocked.lock().expect("[E] top lock").inc(domain);
+        }
+        self.clients.lock().expect("[E] top lock").inc(client);
+    }
+
+    pub fn report(&self, n: usize) -> Report {
+        Report {
+            domains: self.domains.lock().expect("[E] top lock").top(n),
+            blocked: self.blocked.lock().expect("[E] top lock").top(n),
+            clients: self.clients.lock().expect("[E] top lock").top(n),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.domains.lock().expect("[E] top lock").clear();
+        self.blocked.lock().expect("[E] top lock").clear();
+        self.clients.lock().expect("[E] top lock").clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_space_saving() {
+        let mut top = SpaceSaving::new(3);
+        for name in ["a", "a", "a", "a", "b", "b", "b", "c", "d"] {
+            top.inc(name);
+        }
+        // d 替换了次数最少的 c，继承其计数
+        let items = top.top(10);
+        assert_eq!(items.len(), 3);
+        assert_eq!((items[0].name.as_str(), items[0].count), ("a", 4));
+        assert_eq!((items[1].name.as_str(), items[1].count), ("b", 3));
+        assert_eq!(
+            items[2],
+            Item {
+                name: "d".into(),
+                count: 2,
+                error: 1
+            }
+        );
+        assert_eq!(top.top(1).len(), 1);
+
+        let stats = TopStats::from(&TopConfig { capacity: 2 });
+        stats.record("ads.example.com", "192.168.1.2", true);
+        stats.record("example.com", "192.168.1.2", false);
+        let report = stats.report(5);
+        assert_eq!(report.blocked.len(), 1);
+        assert_eq!(report.clients[0].count, 2);
+        stats.reset();
+        assert!(stats.report(5).domains.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! 预编译的域名规则文件，只读映射后二分查找，格式见 `format`
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    io::{self, Error, ErrorKind},
+    path::Path,
+};
+
+use super::format::{checksum, MAGIC, VERSION};
+
+const HEADER_LEN: usize = 24;
+
+fn read_u32(data: &[u8], index: usize) -> u32 {
+    let b = &data[index * 4..index * 4 + 4];
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+pub struct CompiledTrie {
+    mmap: Mmap,
+    count: usize,
+}
+
+impl TryFrom<&Path> for CompiledTrie {
+    type Error = Error;
+
+    fn try_from(filename: &Path) -> io::Result<Self> {
+        let file = File::open(filename)?;
+        // SAFETY: 规则文件只读映射，运行期间不应被原地修改
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::validate(mmap)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{filename:?}: {e}")))
+    }
+}
+
+impl CompiledTrie {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_compiled(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    fn validate(mmap: Mmap) -> Result<Self, String> {
+        if mmap.len() < HEADER_LEN || !Self::is_compiled(&mmap) {
+            return Err("not a compiled trie".into());
+        }
+        let version = read_u32(&mmap[8..], 0);
+        if version != VERSION {
+            return Err(format!("unsupported version {version}, expect {VERSION}"));
+        }
+        let count = read_u32(&mmap[8..], 1) as usize;
+        let expect = u64::from_le_bytes(mmap[16..24].try_into().unwrap());
+        if checksum(&mmap[HEADER_LEN..]) != expect {
+            return Err("checksum mismatch".into());
+        }
+
+        // offsets 与 lines 两张表都要落在文件内，count 来自文件，计算不能溢出
+        let lines_at = count
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|n| n.checked_add(HEADER_LEN))
+            .ok_or("count overflow")?;
+        let keys_at = count
+            .checked_mul(4)
+            .and_then(|n| n.checked_add(lines_at))
+            .ok_or("count overflow")?;
+        if mmap.len() < keys_at {
+            return Err("truncated".into());
+        }
+
+        // 每个键的起止位置递增且不超出 keys，最后一个恰好是 keys 的末尾
+        let keys_len = mmap.len() - keys_at;
+        let offsets = &mmap[HEADER_LEN..lines_at];
+        let mut prev = 0;
+        for i in 0..=count {
+            let offset = read_u32(offsets, i) as usize;
+            if offset < prev || offset > keys_len {
+                return Err(format!("offset {i} out of range"));
+            }
+            prev = offset;
+        }
+        if prev != keys_len {
+            return Err("truncated".into());
+        }
+
+        Ok(Self { mmap, count })
+    }
+
+    fn key(&self, index: usize) -> &[u8] {
+        let offsets = &self.mmap[HEADER_LEN..];
+        let keys = &self.mmap[HEADER_LEN + (self.count * 2 + 1) * 4..];
+        let (start, end) = (read_u32(offsets, index), read_u32(offsets, index + 1));
+        &keys[start as usize..end as usize]
+    }
+
+    fn line(&self, index: usize) -> u32 {
+        read_u32(&self.mmap[HEADER_LEN + (self.count + 1) * 4..], index)
+    }
+
+    fn search(&self, key: &[u8]) -> Option<usize> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.key(mid).cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    /// 前缀匹配，返回最短命中的深度与行号，语义与 `Trie::find` 一致
+    pub fn find<I, T>(&self, values: I) -> Option<(usize, u32)>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let mut key = Vec::with_capacity(64);
+        for (depth, v) in values.into_iter().enumerate() {
+            if depth > 0 {
+                key.push(b'.');
+            }
+            key.extend(v.as_ref().iter().map(u8::to_ascii_lowercase));
+            if let Some(index) = self.search(&key) {
+                return Some((depth + 1, self.line(index)));
+            }
+        }
+        None
+    }
+}
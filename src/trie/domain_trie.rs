@@ -1,30 +1,60 @@
-use super::Trie;
+use super::{CompiledTrie, Trie};
 use std::{
-    fs::read_to_string,
-    io,
+    fmt,
+    fs::{read_to_string, File},
+    io::{self, Read},
     ops::{Deref, DerefMut},
     path::Path,
 };
 
-pub struct DomainTrie(Trie);
+pub struct DomainTrie {
+    trie: Trie,
+    /// (起始标记, 来源)，标记 = 起始标记 + 行号
+    sources: Vec<(u32, String)>,
+    next_tag: u32,
+    /// 文本来源插入的域名数
+    entries: usize,
+    /// 预编译的规则文件直接映射查询，不再展开到 trie
+    compiled: Vec<(CompiledTrie, String)>,
+}
+
+/// 命中的规则：终止匹配的域名后缀及其出处
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainMatch {
+    pub suffix: String,
+    pub source: String,
+    pub line: u32,
+}
+
+impl fmt::Display for DomainMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}:{})", self.suffix, self.source, self.line)
+    }
+}
 
 impl Deref for DomainTrie {
     type Target = Trie;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.trie
     }
 }
 
 impl DerefMut for DomainTrie {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.trie
     }
 }
 
 impl From<Trie> for DomainTrie {
     fn from(trie: Trie) -> Self {
-        Self(trie)
+        Self {
+            trie,
+            sources: Vec::new(),
+            next_tag: 0,
+            entries: 0,
+            compiled: Vec::new(),
+        }
     }
 }
 
@@ -32,12 +62,39 @@ impl TryFrom<&Path> for DomainTrie {
     type Error = io::Error;
 
     fn try_from(filename: &Path) -> Result<Self, Self::Error> {
+        let mut trie: Self = Trie::with_capacity(0).into();
+        trie.extend_file(filename)?;
+        trie.shrink_to_fit();
+        Ok(trie)
+    }
+}
+
+impl DomainTrie {
+    /// 追加一个规则文件，文本或 `build_domains --compile` 生成的编译格式均可
+    pub fn extend_file(&mut self, filename: &Path) -> io::Result<()> {
+        let source = filename.display().to_string();
+        let mut magic = [0; 8];
+        let len = File::open(filename)?.read(&mut magic)?;
+        if CompiledTrie::is_compiled(&magic[..len]) {
+            self.compiled
+                .push((CompiledTrie::try_from(filename)?, source));
+            return Ok(());
+        }
+
         let content = read_to_string(filename)?;
+        self.trie.reserve(content.len() / 20);
+        self.extend_content(&source, &content);
+        Ok(())
+    }
 
-        let estimated_domains = content.len() / 20;
-        let mut trie: Self = Trie::with_capacity(estimated_domains).into();
+    /// 追加一个来源的域名，记录来源以便回溯命中的行
+    pub fn extend_content(&mut self, source: &str, content: &str) {
+        let base = self.next_tag;
+        self.sources.push((base, source.into()));
 
-        for line in content.lines() {
+        let mut lines = 0;
+        for (i, line) in content.lines().enumerate() {
+            lines = i as u32 + 1;
             let line = line.trim();
             if line.is_empty() || line.starts_with('#') {
                 continue;
@@ -46,23 +103,68 @@ impl TryFrom<&Path> for DomainTrie {
             let normalized = line.to_ascii_lowercase();
             let parts: Vec<&[u8]> = normalized.as_bytes().split(|&b| b == b'.').rev().collect();
 
-            trie.insert(parts);
+            self.trie.insert_tagged(parts, base + lines);
+            self.entries += 1;
         }
 
-        trie.shrink_to_fit();
+        self.next_tag = base + lines + 1;
+    }
 
-        Ok(trie)
+    /// 域名条数，含预编译文件
+    pub fn len(&self) -> usize {
+        self.entries + self.compiled.iter().map(|(c, _)| c.len()).sum::<usize>()
+    }
+
+    /// 前缀匹配并返回命中的规则
+    pub fn domain_match<T: AsRef<[u8]>>(&self, reversed_domain: &[T]) -> Option<DomainMatch> {
+        let (depth, source, line) = match self.find(reversed_domain) {
+            Some((depth, tag)) => {
+                let index = self.sources.partition_point(|(base, _)| *base <= tag) - 1;
+                let (base, source) = &self.sources[index];
+                (depth, source, tag - base)
+            }
+            None => self.compiled.iter().find_map(|(compiled, source)| {
+                let (depth, line) = compiled.find(reversed_domain)?;
+                Some((depth, source, line))
+            })?,
+        };
+
+        let suffix: Vec<_> = reversed_domain[..depth]
+            .iter()
+            .rev()
+            .map(|v| String::from_utf8_lossy(v.as_ref()).to_ascii_lowercase())
+            .collect();
+
+        Some(DomainMatch {
+            suffix: suffix.join("."),
+            source: source.clone(),
+            line,
+        })
     }
 }
 
-impl DomainTrie {
-    #[inline]
-    pub fn domain_prefix_match<I, T>(&self, reversed_domain: I) -> bool
-    where
-        I: IntoIterator<Item = T>,
-        T: AsRef<[u8]>,
-        <I as IntoIterator>::IntoIter: DoubleEndedIterator,
-    {
-        self.prefix_match(reversed_domain.into_iter())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_domain_match() {
+        let mut trie: DomainTrie = Trie::with_capacity(0).into();
+        trie.extend_content("a.conf", "# comment\ngoogle.com\n\nYouTube.com\n");
+        trie.extend_content("inline", "corp.example.com");
+
+        let m = trie
+            .domain_match(&["com", "youtube", "www"])
+            .expect("youtube");
+        assert_eq!(m.suffix, "youtube.com");
+        assert_eq!((m.source.as_str(), m.line), ("a.conf", 4));
+
+        let m = trie
+            .domain_match(&["com", "example", "corp", "git"])
+            .expect("corp");
+        assert_eq!(m.to_string(), "corp.example.com (inline:1)");
+
+        assert!(trie.domain_match(&["com", "example"]).is_none());
+        assert!(trie.prefix_match(["com", "google"]));
     }
 }
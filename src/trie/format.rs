@@ -0,0 +1,22 @@
+//! 预编译域名规则文件的格式，读取端与 build_domains 共用
+//!
+//! 布局（小端）：
+//!
+//! ```text
+//! magic    [u8; 8]  "FAKEDNST"
+//! version  u32
+//! count    u32
+//! checksum u64            FNV-1a，覆盖头部之后的全部内容
+//! offsets  [u32; count+1] 每个键在 keys 中的起止位置
+//! lines    [u32; count]   键在原始列表中的行号
+//! keys     [u8]           倒序标签以 '.' 连接，如 "com.google"，按字节序排列
+//! ```
+
+pub const MAGIC: &[u8; 8] = b"FAKEDNST";
+pub const VERSION: u32 = 1;
+
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,207 @@
+use std::{
+    fs::read_to_string,
+    io::{self, Error, ErrorKind},
+    net::IpAddr,
+    path::Path,
+};
+
+use crate::cidr::{ip_bits, Cidr};
+
+/// 二进制前缀树，节点存放在数组中，0 号与 1 号分别为 IPv4 与 IPv6 的根
+#[derive(Debug)]
+pub struct IpTrie {
+    /// 两个子节点的下标，0 表示不存在（根节点不会成为子节点）
+    children: Vec<[u32; 2]>,
+    ends: Vec<bool>,
+    /// 被更短网段覆盖后释放的节点，插入时复用
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl Default for IpTrie {
+    fn default() -> Self {
+        Self {
+            children: vec![[0; 2]; 2],
+            ends: vec![false; 2],
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+/// 地址的第 i 位（从最高位开始）
+fn bit(bits: u128, width: u32, i: u32) -> usize {
+    (bits >> (width - 1 - i) & 1) as usize
+}
+
+fn root_and_width(ip: IpAddr) -> (usize, u32) {
+    match ip {
+        IpAddr::V4(_) => (0, 32),
+        IpAddr::V6(_) => (1, 128),
+    }
+}
+
+impl IpTrie {
+    /// 网段数量
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, cidr: Cidr) {
+        let (mut node, width) = root_and_width(cidr.addr);
+        let bits = ip_bits(cidr.addr);
+
+        for i in 0..cidr.prefix as u32 {
+            // 已被更短的网段覆盖
+            if self.ends[node] {
+                return;
+            }
+            let b = bit(bits, width, i);
+            node = match self.children[node][b] {
+                0 => {
+                    let next = self.alloc();
+                    self.children[node][b] = next as u32;
+                    next
+                }
+                next => next as usize,
+            };
+        }
+
+        if !self.ends[node] {
+            self.ends[node] = true;
+            // 更长的网段已无意义
+            let covered = self.release_children(node);
+            self.len = self.len + 1 - covered;
+        }
+    }
+
+    fn alloc(&mut self) -> usize {
+        if let Some(node) = self.free.pop() {
+            return node as usize;
+        }
+        self.children.push([0; 2]);
+        self.ends.push(false);
+        self.children.len() - 1
+    }
+
+    /// 释放 node 下的全部节点，返回其中网段的数量
+    fn release_children(&mut self, node: usize) -> usize {
+        let mut stack = std::mem::take(&mut self.children[node]).to_vec();
+        let mut covered = 0;
+        while let Some(next) = stack.pop() {
+            if next == 0 {
+                continue;
+            }
+            let next = next as usize;
+            stack.extend(std::mem::take(&mut self.children[next]));
+            if std::mem::take(&mut self.ends[next]) {
+                covered += 1;
+            }
+            self.free.push(next as u32);
+        }
+        covered
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (mut node, width) = root_and_width(ip);
+        let bits = ip_bits(ip);
+
+        for i in 0..width {
+            if self.ends[node] {
+                return true;
+            }
+            node = match self.children[node][bit(bits, width, i)] {
+                0 => return false,
+                next => next as usize,
+            };
+        }
+        self.ends[node]
+    }
+
+    /// 每行一个网段，`#` 开头为注释
+    pub fn extend_content(&mut self, source: &str, content: &str) -> io::Result<()> {
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cidr = line.parse::<Cidr>().map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("{source}:{}: {e}", i + 1))
+            })?;
+            self.insert(cidr);
+        }
+        Ok(())
+    }
+
+    pub fn extend_file(&mut self, filename: &Path) -> io::Result<()> {
+        let content = read_to_string(filename)?;
+        self.children.reserve(content.len() / 4);
+        self.ends.reserve(content.len() / 4);
+        self.extend_content(&filename.display().to_string(), &content)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.children.shrink_to_fit();
+        self.ends.shrink_to_fit();
+        self.free.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_work_ip_trie() {
+        let mut trie = IpTrie::default();
+        trie.extend_content(
+            "test",
+            "# cn\n1.0.1.0/24\n1.0.2.0/23\n1.0.2.128/25\n240e::/20\n10.1.2.3\n",
+        )
+        .unwrap();
+        assert_eq!(trie.len(), 4);
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(trie.contains(ip("1.0.1.255")));
+        assert!(trie.contains(ip("1.0.3.1")));
+        assert!(!trie.contains(ip("1.0.4.1")));
+        assert!(!trie.contains(ip("1.0.0.255")));
+        assert!(trie.contains(ip("10.1.2.3")));
+        assert!(!trie.contains(ip("10.1.2.4")));
+        assert!(trie.contains(ip("240e:3a1::1")));
+        assert!(!trie.contains(ip("2400::1")));
+        // IPv4 网段不匹配 IPv6 地址
+        assert!(!trie.contains(ip("::101:1")));
+
+        let mut all = IpTrie::default();
+        all.insert("0.0.0.0/0".parse().unwrap());
+        assert!(all.contains(ip("8.8.8.8")));
+        assert!(!all.contains(ip("::1")));
+
+        // 后插入的短网段覆盖已有的长网段
+        let mut covered = IpTrie::default();
+        covered
+            .extend_content(
+                "test",
+                "10.1.0.0/16
+10.2.3.0/24
+10.2.4.0/24
+",
+            )
+            .unwrap();
+        let nodes = covered.children.len();
+        covered.insert("10.0.0.0/8".parse().unwrap());
+        assert_eq!(covered.len(), 1);
+        assert!(covered.contains(ip("10.9.9.9")));
+        covered.insert("11.2.3.0/24".parse().unwrap());
+        assert_eq!(covered.len(), 2);
+        assert_eq!(covered.children.len(), nodes);
+
+        let e = trie.extend_content("bad", "1.2.3.0/24\nnot-an-ip\n");
+        assert!(e.unwrap_err().to_string().starts_with("bad:2:"));
+    }
+}
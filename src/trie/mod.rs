@@ -1,5 +1,11 @@
+mod compiled;
 mod domain_trie;
+mod format;
+mod ip_trie;
+#[allow(clippy::module_inception)]
 mod trie;
 
-pub use domain_trie::DomainTrie;
+pub use compiled::CompiledTrie;
+pub use domain_trie::{DomainMatch, DomainTrie};
+pub use ip_trie::IpTrie;
 pub use trie::Trie;
@@ -3,21 +3,22 @@ use ahash::AHashMap;
 #[derive(Debug)]
 struct TrieNode {
     children: AHashMap<Box<[u8]>, TrieNode>,
-    is_end: bool,
+    /// 结束节点携带插入时的标记
+    end: Option<u32>,
 }
 
 impl TrieNode {
     fn new() -> Self {
         TrieNode {
             children: AHashMap::new(),
-            is_end: false,
+            end: None,
         }
     }
 
     fn with_capacity(capacity: usize) -> Self {
         TrieNode {
             children: AHashMap::with_capacity(capacity),
-            is_end: false,
+            end: None,
         }
     }
 }
@@ -35,7 +36,17 @@ impl Trie {
     }
 
     /// 插入字节序列
+    #[cfg(test)]
     pub fn insert<I, T>(&mut self, values: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.insert_tagged(values, 0)
+    }
+
+    /// 插入字节序列并附带标记，已被更短前缀覆盖时保留原标记
+    pub fn insert_tagged<I, T>(&mut self, values: I, tag: u32)
     where
         I: IntoIterator<Item = T>,
         T: AsRef<[u8]>,
@@ -50,12 +61,16 @@ impl Trie {
                 .entry(v.into())
                 .or_insert_with(TrieNode::new);
 
-            if current.is_end {
+            if current.end.is_some() {
                 return;
             }
         }
 
-        current.is_end = true;
+        current.end = Some(tag);
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.root.children.reserve(additional);
     }
 
     /// 构建后压缩内存
@@ -70,28 +85,34 @@ impl Trie {
     }
 
     /// 前缀匹配（支持字节序列）
+    #[cfg(test)]
     #[inline]
     pub fn prefix_match<I, T>(&self, values: I) -> bool
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.find(values).is_some()
+    }
+
+    /// 前缀匹配，返回匹配的深度与标记
+    #[inline]
+    pub fn find<I, T>(&self, values: I) -> Option<(usize, u32)>
     where
         I: IntoIterator<Item = T>,
         T: AsRef<[u8]>,
     {
         let mut current = &self.root;
 
-        for v in values {
+        for (depth, v) in values.into_iter().enumerate() {
             let v = v.as_ref();
-            match current.children.get(v) {
-                Some(node) => {
-                    current = node;
-                    if current.is_end {
-                        return true;
-                    }
-                }
-                None => return false,
+            current = current.children.get(v)?;
+            if let Some(tag) = current.end {
+                return Some((depth + 1, tag));
             }
         }
 
-        false
+        None
     }
 }
 
@@ -112,13 +133,19 @@ fn test() {
     assert!(trie.prefix_match(["a", "p", "p", "l", "e"]));
     assert!(trie.prefix_match(["a", "p", "p", "l", "l"]));
 
-    assert!(!trie.prefix_match(&["g", "r"]));
-    assert!(!trie.prefix_match(&["p", "e", "a"]));
+    assert!(!trie.prefix_match(["g", "r"]));
+    assert!(!trie.prefix_match(["p", "e", "a"]));
 
-    assert!(trie.prefix_match(&["c"]));
-    assert!(trie.prefix_match(&["c", "s"]));
+    assert!(trie.prefix_match(["c"]));
+    assert!(trie.prefix_match(["c", "s"]));
 
-    assert!(trie.prefix_match(&["a", "p", "p", "l", "e"]));
-    assert!(trie.prefix_match(&["b", "a", "n", "a", "n", "a"]));
-    assert!(!trie.prefix_match(&["o", "r", "a", "n", "g", "e"]));
+    assert!(trie.prefix_match(["a", "p", "p", "l", "e"]));
+    assert!(trie.prefix_match(["b", "a", "n", "a", "n", "a"]));
+    assert!(!trie.prefix_match(["o", "r", "a", "n", "g", "e"]));
+
+    trie.insert_tagged(["o", "r"], 7);
+    trie.insert_tagged(["o", "r", "a"], 8);
+    assert_eq!(trie.find(["o", "r", "a", "n"]), Some((2, 7)));
+    assert_eq!(trie.find(["a", "p", "p", "l"]), Some((3, 0)));
+    assert_eq!(trie.find(["o"]), None);
 }
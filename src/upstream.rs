@@ -0,0 +1,293 @@
+use std::{
+    io::Result,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel,
+    config::UpstreamConfig,
+    dns::{Dns, DnsCommand, Reply},
+    dnstap::Dnstap,
+    metrics::{Metrics, ServerMetrics},
+    payload::Payload,
+    poison::PoisonGuard,
+    supervisor::supervise,
+};
+
+const MAX_BUFFER: usize = 5;
+// 300ms
+pub const QUERY_TIMEOUT: u64 = 300;
+
+/// 组内的一台服务器，移出组时停止接收应答
+struct Server {
+    dns: Dns,
+    tx: mpsc::Sender<DnsCommand>,
+    metrics: Arc<ServerMetrics>,
+    response: JoinHandle<()>,
+    rotate: Option<JoinHandle<()>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // 发送端全部释放后 work_cmd 自行退出
+        self.response.abort();
+        if let Some(rotate) = &self.rotate {
+            rotate.abort();
+        }
+    }
+}
+
+/// 一组上游服务器，查询按轮询分发
+pub struct Upstream {
+    pub name: String,
+    guard: Arc<PoisonGuard>,
+    dnstap: Option<Dnstap>,
+    /// 源端口的更换间隔
+    rotate: Option<Duration>,
+    servers: RwLock<Vec<Server>>,
+    next_server: AtomicUsize,
+}
+
+impl Upstream {
+    pub async fn new(
+        name: &str,
+        config: &UpstreamConfig,
+        dnstap: Option<&Dnstap>,
+        metrics: &Metrics,
+    ) -> Result<Self> {
+        let upstream = Self {
+            name: name.into(),
+            guard: Arc::new(PoisonGuard::from(config)),
+            dnstap: dnstap.cloned(),
+            rotate: (config.rotate > 0).then(|| Duration::from_secs(config.rotate)),
+            servers: RwLock::new(Vec::new()),
+            next_server: AtomicUsize::new(0),
+        };
+        upstream.set_servers(&config.servers, metrics).await?;
+        Ok(upstream)
+    }
+
+    async fn start_server(&self, addr: &str, metrics: &Metrics) -> Result<Server> {
+        let server_metrics = metrics.register_server(&self.name, addr);
+        let dns = Dns::new(
+            addr,
+            self.guard.clone(),
+            self.dnstap.clone(),
+            server_metrics.clone(),
+        )
+        .await
+        .inspect_err(|_| metrics.unregister_server(&server_metrics))?;
+
+        let (tx, rx) = mpsc::channel::<DnsCommand>(MAX_BUFFER);
+        let rx = Arc::new(Mutex::new(rx));
+        let task = format!("upstream {} {addr}", self.name);
+        let dns_cloned = dns.clone();
+        supervise(
+            &format!("{task} cmd"),
+            metrics.restarts.clone(),
+            move || {
+                let dns = dns_cloned.clone();
+                let rx = rx.clone();
+                async move {
+                    dns.work_cmd(&mut *rx.lock().await).await;
+                    Ok(())
+                }
+            },
+        );
+        let dns_cloned = dns.clone();
+        let response = supervise(
+            &format!("{task} response"),
+            metrics.restarts.clone(),
+            move || {
+                let dns = dns_cloned.clone();
+                async move {
+                    dns.work_response().await;
+                    Ok(())
+                }
+            },
+        );
+        let rotate = self.rotate.map(|every| {
+            let dns = dns.clone();
+            supervise(
+                &format!("{task} rotate"),
+                metrics.restarts.clone(),
+                move || {
+                    let dns = dns.clone();
+                    async move {
+                        dns.conn().rotate(every).await;
+                        Ok(())
+                    }
+                },
+            )
+        });
+        Ok(Server {
+            dns,
+            tx,
+            metrics: server_metrics,
+            response,
+            rotate,
+        })
+    }
+
+    /// 替换组内的服务器，旧服务器上未完成的查询按超时处理；任一服务器启动失败时保持原样
+    pub async fn set_servers(&self, addrs: &[String], metrics: &Metrics) -> Result<()> {
+        let mut servers = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            match self.start_server(addr, metrics).await {
+                Ok(server) => servers.push(server),
+                Err(e) => {
+                    for server in &servers {
+                        metrics.unregister_server(&server.metrics);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let old = std::mem::replace(
+            &mut *self.servers.write().expect("[E] upstream servers lock"),
+            servers,
+        );
+        for server in &old {
+            metrics.unregister_server(&server.metrics);
+        }
+        if !old.is_empty() {
+            info!(upstream = %self.name, servers = ?addrs, "upstream servers replaced");
+        }
+        Ok(())
+    }
+
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        self.dns().iter().map(Dns::remote).collect()
+    }
+
+    fn dns(&self) -> Vec<Dns> {
+        self.servers
+            .read()
+            .expect("[E] upstream servers lock")
+            .iter()
+            .map(|server| server.dns.clone())
+            .collect()
+    }
+
+    pub async fn flush_cache(&self, name: Option<&str>) -> usize {
+        let mut flushed = 0;
+        for dns in self.dns() {
+            flushed += dns.flush_cache(name).await;
+        }
+        flushed
+    }
+
+    /// 各服务器的缓存条目：(服务器, 域名, 类型, 剩余时间)
+    pub async fn cache_entries(&self) -> Vec<(SocketAddr, String, u16, Duration)> {
+        let mut entries = Vec::new();
+        for dns in self.dns() {
+            let remote = dns.remote();
+            entries.extend(
+                dns.cache_entries()
+                    .await
+                    .into_iter()
+                    .map(|(name, qtype, ttl)| (remote, name, qtype, ttl)),
+            );
+        }
+        entries
+    }
+
+    /// 各服务器未过期的缓存应答：(服务器, 过期时间, 应答)
+    pub async fn export_cache(&self) -> Vec<(SocketAddr, u64, Payload)> {
+        let mut entries = Vec::new();
+        for dns in self.dns() {
+            let remote = dns.remote();
+            entries.extend(
+                dns.export_cache()
+                    .await
+                    .into_iter()
+                    .map(|(expires_at, payload)| (remote, expires_at, payload)),
+            );
+        }
+        entries
+    }
+
+    /// 服务器已不在组内时忽略
+    pub async fn import_cache(&self, server: SocketAddr, entries: Vec<(u64, Payload)>) -> usize {
+        match self.dns().into_iter().find(|dns| dns.remote() == server) {
+            Some(dns) => dns.import_cache(entries).await,
+            None => 0,
+        }
+    }
+
+    /// 转发请求并等待应答，超时或出错时返回 SERVFAIL
+    pub async fn query(&self, mut payload: Payload) -> Reply {
+        let tx = {
+            let servers = self.servers.read().expect("[E] upstream servers lock");
+            match servers.len() {
+                0 => None,
+                len => {
+                    let server = &servers[self.next_server.fetch_add(1, Ordering::Relaxed) % len];
+                    Some((server.tx.clone(), server.dns.clone()))
+                }
+            }
+        };
+        let Some((tx, dns)) = tx else {
+            error!(upstream = %self.name, "upstream has no servers");
+            payload.servfail();
+            return Reply {
+                payload,
+                cached: false,
+            };
+        };
+
+        // 不同客户端的请求 id 可能相同，发往上游前换成随机且未在等待中的 id
+        let client_id = payload.id();
+        let (resp, rx) = oneshot::channel::<Reply>();
+        let id = dns.register(&mut payload, resp).await;
+
+        let query = DnsCommand::Query {
+            payload: payload.clone(),
+        };
+        if tx.send(query).await.is_err() {
+            error!(upstream = %self.name, "upstream server stopped");
+            payload.servfail();
+            payload.set_id(client_id);
+            return Reply {
+                payload,
+                cached: false,
+            };
+        }
+
+        let mut reply = match cancel!(rx, QUERY_TIMEOUT) {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => {
+                error!(upstream = %self.name, error = ?e, "upstream rx");
+                payload.servfail();
+                Reply {
+                    payload,
+                    cached: false,
+                }
+            }
+            Err(e) => {
+                warn!(upstream = %self.name, error = ?e, "upstream query");
+                // 服务器已移出组时无需清理
+                let _ = tx.send(DnsCommand::TimedOut { id }).await;
+                payload.servfail();
+                Reply {
+                    payload,
+                    cached: false,
+                }
+            }
+        };
+
+        reply.payload.set_id(client_id);
+        reply
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_to_string,
+    io::{Error, ErrorKind, Result},
+};
+
+use crate::{
+    config::ZoneConfig,
+    message::{rcode, rtype, Message, RData, Record},
+};
+
+const MAX_CNAME_CHAIN: usize = 8;
+const DEFAULT_TTL: u32 = 3600;
+
+/// 一条逻辑记录（括号内的多行已合并）
+struct Entry {
+    line: usize,
+    owner_omitted: bool,
+    tokens: Vec<String>,
+}
+
+/// 按 RFC 1035 5.1 拆分：`;` 注释、括号续行、引号字符串
+fn entries(content: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut depth = 0;
+    let mut entry: Option<Entry> = None;
+
+    for (i, line) in content.lines().enumerate() {
+        let current = entry.get_or_insert_with(|| Entry {
+            line: i + 1,
+            owner_omitted: line.starts_with([' ', '\t']),
+            tokens: Vec::new(),
+        });
+
+        let mut chars = line.chars().peekable();
+        let mut token = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' => return Err(syntax(i + 1, "unbalanced ')'")),
+                '"' => {
+                    token.push('"');
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => {
+                                token.push('\\');
+                                token.extend(chars.next());
+                            }
+                            Some(c) => token.push(c),
+                            None => return Err(syntax(i + 1, "unterminated string")),
+                        }
+                    }
+                    token.push('"');
+                    continue;
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    token.push(c);
+                    if !chars
+                        .peek()
+                        .is_some_and(|n| n.is_whitespace() || "();\"".contains(*n))
+                    {
+                        continue;
+                    }
+                }
+            }
+            if !token.is_empty() {
+                current.tokens.push(std::mem::take(&mut token));
+            }
+        }
+        if !token.is_empty() {
+            current.tokens.push(token);
+        }
+
+        if depth == 0 {
+            if let Some(entry) = entry.take().filter(|e| !e.tokens.is_empty()) {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(syntax(content.lines().count(), "unbalanced '('"));
+    }
+    Ok(entries)
+}
+
+fn syntax(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {line}: {msg}"))
+}
+
+/// 解析 TTL，支持 1h30m 这类单位，溢出时返回 None
+fn parse_ttl(token: &str) -> Option<u32> {
+    if let Ok(ttl) = token.parse() {
+        return Some(ttl);
+    }
+    let (mut total, mut n) = (0u32, None::<u32>);
+    for c in token.to_ascii_lowercase().chars() {
+        match c {
+            '0'..='9' => {
+                n = Some(
+                    n.unwrap_or(0)
+                        .checked_mul(10)?
+                        .checked_add(c.to_digit(10)?)?,
+                )
+            }
+            unit => {
+                let scale = match unit {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    'w' => 604800,
+                    _ => return None,
+                };
+                total = total.checked_add(n.take()?.checked_mul(scale)?)?;
+            }
+        }
+    }
+    (n.is_none() && total > 0).then_some(total)
+}
+
+fn in_zone(name: &str, origin: &str) -> bool {
+    name == origin
+        || name
+            .strip_suffix(origin)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// 由区域根向下依次返回 name 的各级祖先（含 name 本身），name 须在区域内
+fn ancestors<'a>(name: &'a str, origin: &'a str) -> impl Iterator<Item = &'a str> {
+    let relative = name.len() - origin.len();
+    let mut starts: Vec<usize> = Vec::new();
+    if relative > 0 {
+        starts.push(0);
+        starts.extend(
+            name[..relative]
+                .match_indices('.')
+                .map(|(i, _)| i + 1)
+                .filter(|&s| s < relative),
+        );
+        starts.reverse();
+    }
+    std::iter::once(origin).chain(starts.into_iter().map(move |s| &name[s..]))
+}
+
+pub struct Zone {
+    origin: String,
+    soa: Record,
+    records: HashMap<String, Vec<Record>>,
+    /// 所有存在的节点，含空的中间节点
+    nodes: HashSet<String>,
+}
+
+impl TryFrom<&ZoneConfig> for Zone {
+    type Error = Error;
+
+    fn try_from(config: &ZoneConfig) -> Result<Self> {
+        let content = read_to_string(&config.file)
+            .map_err(|e| Error::new(e.kind(), format!("zone {:?}: {e}", config.file)))?;
+        Self::parse(&config.name, &content)
+            .map_err(|e| Error::new(e.kind(), format!("zone {:?}: {e}", config.file)))
+    }
+}
+
+impl Zone {
+    pub fn parse(name: &str, content: &str) -> Result<Self> {
+        let zone = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut origin = zone.clone();
+        let mut default_ttl = None;
+        let mut last_owner: Option<String> = None;
+        let mut last_ttl = None;
+        let mut records: Vec<Record> = Vec::new();
+
+        let absolute = |name: &str, origin: &str| -> String {
+            let name = name.to_ascii_lowercase();
+            match name.as_str() {
+                "@" => origin.to_string(),
+                n if n.ends_with('.') => n.trim_end_matches('.').to_string(),
+                n if origin.is_empty() => n.to_string(),
+                n => format!("{n}.{origin}"),
+            }
+        };
+
+        for entry in entries(content)? {
+            let err = |msg: &str| syntax(entry.line, msg);
+            let mut tokens = entry.tokens.iter().map(String::as_str).peekable();
+
+            match tokens.peek() {
+                Some(&"$ORIGIN") => {
+                    let name = tokens.nth(1).ok_or_else(|| err("$ORIGIN without name"))?;
+                    origin = absolute(name, &origin);
+                    continue;
+                }
+                Some(&"$TTL") => {
+                    let ttl = tokens.nth(1).and_then(parse_ttl);
+                    default_ttl = Some(ttl.ok_or_else(|| err("invalid $TTL"))?);
+                    continue;
+                }
+                Some(directive) if directive.starts_with('$') => {
+                    return Err(err(&format!("unsupported directive {directive}")));
+                }
+                _ => {}
+            }
+
+            let owner = if entry.owner_omitted {
+                last_owner.clone().ok_or_else(|| err("missing owner"))?
+            } else {
+                absolute(tokens.next().unwrap(), &origin)
+            };
+            if !in_zone(&owner, &zone) {
+                return Err(err(&format!("{owner} is out of zone {zone}")));
+            }
+
+            // TTL 与 class 顺序任意
+            let mut ttl = None;
+            let rtype = loop {
+                let token = tokens.next().ok_or_else(|| err("missing type"))?;
+                if token.eq_ignore_ascii_case("IN") {
+                    continue;
+                }
+                if let Some(v) = parse_ttl(token).filter(|_| ttl.is_none()) {
+                    ttl = Some(v);
+                    continue;
+                }
+                break rtype::from_name(token)
+                    .ok_or_else(|| err(&format!("unknown type {token}")))?;
+            };
+
+            let mut fields: Vec<String> = tokens.map(String::from).collect();
+            let name_fields: &[usize] = match rtype {
+                rtype::CNAME | rtype::NS | rtype::PTR => &[0],
+                rtype::MX => &[1],
+                rtype::SRV => &[3],
+                rtype::SOA => &[0, 1],
+                _ => &[],
+            };
+            for &i in name_fields {
+                if let Some(field) = fields.get_mut(i) {
+                    *field = absolute(field, &origin);
+                }
+            }
+            if rtype == rtype::SOA {
+                for field in fields.iter_mut().skip(2) {
+                    if let Some(v) = parse_ttl(field) {
+                        *field = v.to_string();
+                    }
+                }
+            }
+            let data =
+                RData::from_text(rtype, &fields.join(" ")).map_err(|e| err(&e.to_string()))?;
+
+            let ttl = ttl.or(default_ttl).or(last_ttl).unwrap_or(DEFAULT_TTL);
+            last_owner = Some(owner.clone());
+            last_ttl = Some(ttl);
+            records.push(Record::with_type(&owner, rtype, ttl, data));
+        }
+
+        let soa = records
+            .iter()
+            .find(|r| r.rtype == rtype::SOA && r.name == zone)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing SOA at zone apex"))?;
+
+        let mut zone_records: HashMap<String, Vec<Record>> = HashMap::new();
+        let mut nodes = HashSet::new();
+        for record in records {
+            for node in ancestors(&record.name, &zone) {
+                nodes.insert(node.to_string());
+            }
+            zone_records
+                .entry(record.name.clone())
+                .or_default()
+                .push(record);
+        }
+
+        Ok(Self {
+            origin: zone,
+            soa,
+            records: zone_records,
+            nodes,
+        })
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        in_zone(name, &self.origin)
+    }
+
+    /// 否定应答在权威段携带 SOA，TTL 取 SOA 的 TTL 与 minimum 较小者（RFC 2308）
+    fn negative(&self, reply: &mut Message) {
+        let mut soa = self.soa.clone();
+        if let RData::Soa { minimum, .. } = soa.data {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+        reply.authorities.push(soa);
+    }
+
+    /// 位于区域切割点之下时返回子区域的 NS 记录
+    fn delegation(&self, name: &str) -> Option<&[Record]> {
+        ancestors(name, &self.origin).skip(1).find_map(|node| {
+            let records = self.records.get(node)?;
+            records
+                .iter()
+                .any(|r| r.rtype == rtype::NS)
+                .then_some(records.as_slice())
+        })
+    }
+
+    /// 精确匹配，否则尝试最近祖先处的通配符，合成的记录以查询名为属主
+    fn lookup(&self, name: &str) -> Option<Vec<Record>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+        if self.nodes.contains(name) {
+            return None;
+        }
+
+        let encloser = ancestors(name, &self.origin)
+            .filter(|node| self.nodes.contains(*node))
+            .last()?;
+        let wildcard = self.records.get(&format!("*.{encloser}"))?;
+        Some(
+            wildcard
+                .iter()
+                .map(|r| Record {
+                    name: name.to_string(),
+                    ..r.clone()
+                })
+                .collect(),
+        )
+    }
+
+    fn glue(&self, ns: &[Record], reply: &mut Message) {
+        for record in ns.iter().filter(|r| r.rtype == rtype::NS) {
+            reply.authorities.push(record.clone());
+            let RData::Name(target) = &record.data else {
+                continue;
+            };
+            if let Some(addrs) = self.records.get(target) {
+                reply.additionals.extend(
+                    addrs
+                        .iter()
+                        .filter(|r| r.rtype == rtype::A || r.rtype == rtype::AAAA)
+                        .cloned(),
+                );
+            }
+        }
+    }
+
+    pub fn answer(&self, query: &Message) -> Result<Message> {
+        let question = query
+            .question()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "query without question"))?;
+        let mut reply = Message::reply(query);
+        // 应答的问题部分保留查询的大小写，查找不区分大小写
+        let qname = question.name.to_ascii_lowercase();
+
+        if let Some(ns) = self.delegation(&qname) {
+            self.glue(ns, &mut reply);
+            return Ok(reply);
+        }
+        reply.set_authoritative(true);
+
+        let mut name = qname;
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(records) = self.lookup(&name) else {
+                if !self.nodes.contains(&name) {
+                    reply.set_rcode(rcode::NXDOMAIN);
+                }
+                self.negative(&mut reply);
+                break;
+            };
+
+            let matched: Vec<Record> = records
+                .iter()
+                .filter(|r| r.rtype == question.qtype || question.qtype == rtype::ANY)
+                .cloned()
+                .collect();
+            if !matched.is_empty() {
+                reply.answers.extend(matched);
+                break;
+            }
+
+            let cname = records.iter().find(|r| r.rtype == rtype::CNAME);
+            match cname.map(|r| (r, &r.data)) {
+                Some((record, RData::Name(target))) => {
+                    reply.answers.push(record.clone());
+                    if !self.contains(target) || self.delegation(target).is_some() {
+                        break;
+                    }
+                    name = target.clone();
+                }
+                _ => {
+                    self.negative(&mut reply);
+                    break;
+                }
+            }
+        }
+
+        Ok(reply)
+    }
+}
+
+/// 多个区域按最长匹配选择
+#[derive(Default)]
+pub struct Zones(Vec<Zone>);
+
+impl TryFrom<&[ZoneConfig]> for Zones {
+    type Error = Error;
+
+    fn try_from(configs: &[ZoneConfig]) -> Result<Self> {
+        let mut zones = configs
+            .iter()
+            .map(Zone::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        zones.sort_by_key(|z| std::cmp::Reverse(z.origin.len()));
+        Ok(Self(zones))
+    }
+}
+
+impl Zones {
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        self.0.iter().find(|zone| zone.contains(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Zone> {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = r#"
+$TTL 1h
+@   IN SOA ns1 hostmaster (
+        2024010101 ; serial
+        1h 15m 1w 300 )
+    IN NS  ns1
+ns1     A     10.0.0.1
+www 300 IN A  10.0.0.10
+        IN TXT "v=spf1 -all; really"
+api     CNAME www
+mail    MX    10 mx.staging.example.com.
+_sip._tcp SRV 0 5 5060 sip
+sip     A     10.0.0.20
+*.apps  A     10.0.0.30
+sub     NS    ns.sub
+ns.sub  A     10.0.1.1
+"#;
+
+    fn answer(zone: &Zone, name: &str, qtype: u16) -> Message {
+        zone.answer(&Message::query(name, qtype)).unwrap()
+    }
+
+    #[test]
+    fn it_work_parse() {
+        let zone = Zone::parse("staging.example.com.", ZONE).unwrap();
+        assert_eq!(zone.origin(), "staging.example.com");
+        assert_eq!(zone.soa.ttl, 3600);
+        assert!(matches!(
+            zone.soa.data,
+            RData::Soa {
+                serial: 2024010101,
+                refresh: 3600,
+                retry: 900,
+                ..
+            }
+        ));
+        let www = &zone.records["www.staging.example.com"];
+        assert_eq!(www[0].ttl, 300);
+        assert_eq!(
+            www[1].data,
+            RData::Txt(vec![b"v=spf1 -all; really".to_vec()])
+        );
+        assert!(zone.nodes.contains("_tcp.staging.example.com"));
+    }
+
+    #[test]
+    fn it_work_answer() {
+        let zone = Zone::parse("staging.example.com", ZONE).unwrap();
+
+        let reply = answer(&zone, "api.staging.example.com", rtype::A);
+        assert_eq!(reply.flags & 0x0400, 0x0400);
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[1].data, RData::A(Ipv4Addr::new(10, 0, 0, 10)));
+
+        let reply = answer(&zone, "_sip._tcp.staging.example.com", rtype::SRV);
+        assert_eq!(reply.answers.len(), 1);
+
+        // NODATA：空的中间节点与无该类型的名称
+        for name in ["_tcp.staging.example.com", "www.staging.example.com"] {
+            let reply = answer(&zone, name, rtype::MX);
+            assert_eq!((reply.flags & 0x000f, reply.answers.len()), (0, 0));
+            assert_eq!(reply.authorities[0].rtype, rtype::SOA);
+            assert_eq!(reply.authorities[0].ttl, 300);
+        }
+
+        let reply = answer(&zone, "nope.staging.example.com", rtype::A);
+        assert_eq!(reply.flags & 0x000f, rcode::NXDOMAIN as u16);
+        assert_eq!(reply.authorities[0].rtype, rtype::SOA);
+
+        let reply = answer(&zone, "a.b.apps.staging.example.com", rtype::A);
+        assert_eq!(reply.answers[0].name, "a.b.apps.staging.example.com");
+        assert_eq!(reply.answers[0].data, RData::A(Ipv4Addr::new(10, 0, 0, 30)));
+
+        // 委派：非权威，NS 在权威段，胶水记录在附加段
+        let reply = answer(&zone, "host.sub.staging.example.com", rtype::A);
+        assert_eq!(reply.flags & 0x0400, 0);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.authorities[0].rtype, rtype::NS);
+        assert_eq!(
+            reply.additionals[0].data,
+            RData::A(Ipv4Addr::new(10, 0, 1, 1))
+        );
+    }
+
+    #[test]
+    fn it_work_errors() {
+        assert!(Zone::parse("example.com", "www A 1.2.3.4\n").is_err());
+        assert!(Zone::parse("example.com", "@ SOA ns hm ( 1 2 3 4 5\n").is_err());
+        assert!(Zone::parse("example.com", "other.org. A 1.2.3.4\n").is_err());
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("h"), None);
+        assert_eq!(parse_ttl("7102w"), None);
+        assert_eq!(parse_ttl("4294967296s"), None);
+    }
+}